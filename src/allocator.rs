@@ -0,0 +1,473 @@
+use serde::Deserialize;
+use std::io;
+use std::ops::{Deref, DerefMut};
+
+/// Where to place an inaccessible guard page relative to a buffer
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GuardPage {
+    /// Allocate the buffer normally, on the heap
+    #[default]
+    None,
+    /// Place the buffer directly after a guard page, to catch under-reads and under-writes
+    Start,
+    /// Place the buffer directly before a guard page, to catch over-reads and over-writes
+    End,
+}
+
+/// A buffer that is passed to the driver, allocated according to the IOCTL's guard page
+/// setting. Derefs to the bytes of the buffer either way.
+#[derive(Debug)]
+pub enum IoBuffer {
+    Heap(Vec<u8>),
+    Guarded(GuardedBuffer),
+}
+
+impl IoBuffer {
+    /// Allocates a buffer holding a copy of `bytes`, flush against a guard page if requested
+    pub fn from_slice(bytes: &[u8], guard_page: GuardPage) -> io::Result<IoBuffer> {
+        let mut buffer = IoBuffer::zeroed(bytes.len(), guard_page)?;
+        buffer.copy_from_slice(bytes);
+
+        Ok(buffer)
+    }
+
+    /// Allocates a zeroed buffer of `len` bytes, flush against a guard page if requested
+    pub fn zeroed(len: usize, guard_page: GuardPage) -> io::Result<IoBuffer> {
+        match guard_page {
+            GuardPage::None => Ok(IoBuffer::Heap(vec![0; len])),
+            guard_page => Ok(IoBuffer::Guarded(GuardedBuffer::new(len, guard_page)?)),
+        }
+    }
+}
+
+impl Deref for IoBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            IoBuffer::Heap(buffer) => buffer,
+            IoBuffer::Guarded(buffer) => buffer,
+        }
+    }
+}
+
+impl DerefMut for IoBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            IoBuffer::Heap(buffer) => buffer,
+            IoBuffer::Guarded(buffer) => buffer,
+        }
+    }
+}
+
+/// A zeroed buffer placed in its own mapping, with no gap between the buffer and an
+/// inaccessible guard page, so that any access just outside of it faults.
+#[derive(Debug)]
+pub struct GuardedBuffer {
+    /// The whole mapping, including the guard page
+    mapping: Mapping,
+    /// Offset of the buffer within the mapping
+    offset: usize,
+    len: usize,
+}
+
+impl GuardedBuffer {
+    /// Maps enough pages to hold `len` bytes plus a guard page, then places the buffer
+    /// against the guard page at the start or end of the mapping.
+    pub fn new(len: usize, guard_page: GuardPage) -> io::Result<GuardedBuffer> {
+        let page_size = sys::page_size();
+        let data_len = len.div_ceil(page_size) * page_size;
+        let mapping_len = data_len
+            .checked_add(page_size)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "buffer is too large"))?;
+
+        let (guard_offset, offset) = match guard_page {
+            GuardPage::Start => (0, page_size),
+            GuardPage::End => (data_len, data_len - len),
+            GuardPage::None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "guarded buffers need a guard page placement",
+                ));
+            }
+        };
+
+        let mapping = Mapping::new(mapping_len)?;
+        mapping.protect(guard_offset, page_size, Protection::None)?;
+
+        Ok(GuardedBuffer {
+            mapping,
+            offset,
+            len,
+        })
+    }
+}
+
+impl Deref for GuardedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.mapping.address.add(self.offset), self.len) }
+    }
+}
+
+impl DerefMut for GuardedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.mapping.address.add(self.offset), self.len) }
+    }
+}
+
+/// Hostile values for pointer entries, for checking that a driver validates the user
+/// pointers it is given with `ProbeForRead`/`ProbeForWrite`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostilePointer {
+    /// The start of kernel address space
+    Kernel,
+    /// A user address with nothing accessible mapped at it
+    Unmapped,
+    /// The last accessible byte before an inaccessible page
+    PageBoundary,
+    /// A buffer that has been used and then freed
+    Freed,
+    /// A page that is mapped read-only
+    ReadOnly,
+}
+
+impl HostilePointer {
+    /// Returns the address for a pointer of `width` bytes, using the memory allocated for
+    /// the dispatch. Without any memory, only the kernel address is known and the rest are
+    /// given as 0.
+    pub fn address(self, memory: Option<&HostileMemory>, width: usize) -> u64 {
+        const KERNEL_ADDRESS_32: u64 = 0x80000000;
+        const KERNEL_ADDRESS_64: u64 = 0xFFFF800000000000;
+
+        let Some(memory) = memory else {
+            return match (self, width) {
+                (HostilePointer::Kernel, 4) => KERNEL_ADDRESS_32,
+                (HostilePointer::Kernel, _) => KERNEL_ADDRESS_64,
+                _ => 0,
+            };
+        };
+
+        match self {
+            HostilePointer::Kernel => HostilePointer::Kernel.address(None, width),
+            HostilePointer::Unmapped => memory.unmapped.address as u64,
+            HostilePointer::PageBoundary => {
+                memory.page_boundary.address as u64 + sys::page_size() as u64 - 1
+            }
+            HostilePointer::Freed => memory.freed.address as u64,
+            HostilePointer::ReadOnly => memory.read_only.address as u64,
+        }
+    }
+}
+
+/// Memory behind the hostile pointer values, which has to stay allocated until the IOCTL
+/// has been sent so that the addresses stay as hostile as intended.
+#[derive(Debug)]
+pub struct HostileMemory {
+    /// An accessible page followed by an inaccessible page
+    page_boundary: Mapping,
+    read_only: Mapping,
+    /// Kept reserved but inaccessible, rather than unmapped, so that nothing else can be
+    /// allocated at the address before the IOCTL is sent
+    unmapped: Mapping,
+    /// Pages that were written to and then decommitted, which stay reserved so that the
+    /// address can't be handed out again, e.g. for the IOCTL's own buffers
+    freed: Mapping,
+}
+
+impl HostileMemory {
+    pub fn new() -> io::Result<HostileMemory> {
+        let page_size = sys::page_size();
+
+        let page_boundary = Mapping::new(page_size * 2)?;
+        page_boundary.protect(page_size, page_size, Protection::None)?;
+
+        let read_only = Mapping::new(page_size)?;
+        read_only.protect(0, page_size, Protection::ReadOnly)?;
+
+        let unmapped = Mapping::new(page_size)?;
+        unmapped.protect(0, page_size, Protection::None)?;
+
+        let freed = Mapping::new(page_size)?;
+        unsafe {
+            freed.address.write_bytes(0x41, page_size);
+            sys::decommit(freed.address, page_size)?;
+        }
+
+        Ok(HostileMemory {
+            page_boundary,
+            read_only,
+            unmapped,
+            freed,
+        })
+    }
+}
+
+/// Access that can be given to part of a mapping
+#[derive(Debug, Clone, Copy)]
+enum Protection {
+    None,
+    ReadOnly,
+}
+
+/// Zeroed, read-write pages mapped on their own, which are unmapped on drop
+#[derive(Debug)]
+struct Mapping {
+    address: *mut u8,
+    len: usize,
+}
+
+// The mapping is owned by the Mapping alone, the same as a Vec's allocation
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps `len` bytes, which must be a multiple of the page size
+    fn new(len: usize) -> io::Result<Mapping> {
+        Ok(Mapping {
+            address: sys::map(len)?,
+            len,
+        })
+    }
+
+    /// Changes the access to `len` bytes from `offset`, which must both be page aligned
+    fn protect(&self, offset: usize, len: usize, protection: Protection) -> io::Result<()> {
+        assert!(offset + len <= self.len);
+
+        unsafe { sys::protect(self.address.add(offset), len, protection) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { sys::unmap(self.address, self.len) };
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::Protection;
+    use std::io;
+    use std::ptr;
+
+    pub fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    /// Maps zeroed, read-write memory
+    pub fn map(len: usize) -> io::Result<*mut u8> {
+        let mapping = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if mapping == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(mapping as *mut u8)
+    }
+
+    /// Changes the access to a page-aligned range
+    pub unsafe fn protect(address: *mut u8, len: usize, protection: Protection) -> io::Result<()> {
+        let protection = match protection {
+            Protection::None => libc::PROT_NONE,
+            Protection::ReadOnly => libc::PROT_READ,
+        };
+
+        if unsafe { libc::mprotect(address as *mut _, len, protection) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Frees the memory behind a page-aligned range, leaving the addresses reserved and
+    /// inaccessible
+    pub unsafe fn decommit(address: *mut u8, len: usize) -> io::Result<()> {
+        unsafe { protect(address, len, Protection::None)? };
+
+        if unsafe { libc::madvise(address as *mut _, len, libc::MADV_DONTNEED) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    pub unsafe fn unmap(mapping: *mut u8, len: usize) {
+        unsafe { libc::munmap(mapping as *mut _, len) };
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::Protection;
+    use std::io;
+    use windows::Win32::System::Memory::{
+        MEM_COMMIT, MEM_DECOMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_NOACCESS, PAGE_PROTECTION_FLAGS,
+        PAGE_READONLY, PAGE_READWRITE, VirtualAlloc, VirtualFree, VirtualProtect,
+    };
+    use windows::Win32::System::SystemInformation::{GetSystemInfo, SYSTEM_INFO};
+
+    pub fn page_size() -> usize {
+        let mut system_info = SYSTEM_INFO::default();
+        unsafe { GetSystemInfo(&mut system_info) };
+
+        system_info.dwPageSize as usize
+    }
+
+    /// Maps zeroed, read-write memory
+    pub fn map(len: usize) -> io::Result<*mut u8> {
+        let mapping = unsafe { VirtualAlloc(None, len, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE) };
+
+        if mapping.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(mapping as *mut u8)
+    }
+
+    /// Changes the access to a page-aligned range
+    pub unsafe fn protect(address: *mut u8, len: usize, protection: Protection) -> io::Result<()> {
+        let protection = match protection {
+            Protection::None => PAGE_NOACCESS,
+            Protection::ReadOnly => PAGE_READONLY,
+        };
+        let mut old_protect = PAGE_PROTECTION_FLAGS::default();
+
+        unsafe { VirtualProtect(address as *const _, len, protection, &mut old_protect) }
+            .map_err(|_| io::Error::last_os_error())
+    }
+
+    /// Frees the memory behind a page-aligned range, leaving the addresses reserved and
+    /// inaccessible
+    pub unsafe fn decommit(address: *mut u8, len: usize) -> io::Result<()> {
+        unsafe { VirtualFree(address as *mut _, len, MEM_DECOMMIT) }
+            .map_err(|_| io::Error::last_os_error())
+    }
+
+    pub unsafe fn unmap(mapping: *mut u8, _len: usize) {
+        let _ = unsafe { VirtualFree(mapping as *mut _, 0, MEM_RELEASE) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asks the kernel to read a byte from `address`, which returns EFAULT rather than
+    /// crashing if it is inaccessible. This is the same as a driver reading our buffer.
+    #[cfg(unix)]
+    fn kernel_can_read(address: *const u8) -> bool {
+        let mut pipe_fds = [0; 2];
+        assert_eq!(0, unsafe { libc::pipe(pipe_fds.as_mut_ptr()) });
+
+        let written = unsafe { libc::write(pipe_fds[1], address as *const _, 1) };
+
+        unsafe {
+            libc::close(pipe_fds[0]);
+            libc::close(pipe_fds[1]);
+        }
+
+        written == 1
+    }
+
+    #[test]
+    fn io_buffer_from_slice() {
+        for guard_page in [GuardPage::None, GuardPage::Start, GuardPage::End] {
+            let buffer = IoBuffer::from_slice(&[0x41, 0x42, 0x43], guard_page).unwrap();
+            assert_eq!(&[0x41, 0x42, 0x43], &buffer[..]);
+        }
+    }
+
+    /// Asks the kernel to write a byte to `address`, which returns EFAULT rather than
+    /// crashing if it is not writable
+    #[cfg(unix)]
+    fn kernel_can_write(address: *mut u8) -> bool {
+        let mut pipe_fds = [0; 2];
+        assert_eq!(0, unsafe { libc::pipe(pipe_fds.as_mut_ptr()) });
+        assert_eq!(1, unsafe {
+            libc::write(pipe_fds[1], [0x41u8].as_ptr() as *const _, 1)
+        });
+
+        let read = unsafe { libc::read(pipe_fds[0], address as *mut _, 1) };
+
+        unsafe {
+            libc::close(pipe_fds[0]);
+            libc::close(pipe_fds[1]);
+        }
+
+        read == 1
+    }
+
+    /// Asks the kernel whether the page at `address` has memory behind it
+    #[cfg(unix)]
+    fn is_resident(address: *mut u8) -> bool {
+        let mut residency = [0; 1];
+        assert_eq!(0, unsafe {
+            libc::mincore(address as *mut _, sys::page_size(), residency.as_mut_ptr())
+        });
+
+        residency[0] & 1 == 1
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hostile_pointers() {
+        let memory = HostileMemory::new().unwrap();
+        let address = |pointer: HostilePointer| pointer.address(Some(&memory), 8);
+
+        assert_eq!(0xFFFF800000000000, address(HostilePointer::Kernel));
+        assert_eq!(0x80000000, HostilePointer::Kernel.address(Some(&memory), 4));
+
+        assert!(!kernel_can_read(
+            address(HostilePointer::Unmapped) as *const u8
+        ));
+
+        let page_boundary = address(HostilePointer::PageBoundary) as *const u8;
+        assert!(kernel_can_read(page_boundary));
+        assert!(!kernel_can_read(unsafe { page_boundary.add(1) }));
+
+        let read_only = address(HostilePointer::ReadOnly) as *mut u8;
+        assert!(kernel_can_read(read_only));
+        assert!(!kernel_can_write(read_only));
+
+        let freed = address(HostilePointer::Freed) as *mut u8;
+        assert!(!kernel_can_read(freed));
+        assert!(!is_resident(freed));
+        assert_eq!(0, HostilePointer::Freed.address(None, 8));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn guard_page_end() {
+        let mut buffer = GuardedBuffer::new(0x10, GuardPage::End).unwrap();
+        buffer.fill(0x41);
+
+        let end = buffer.as_ptr_range().end;
+        assert_eq!(0, end as usize % sys::page_size());
+        assert!(kernel_can_read(unsafe { end.sub(1) }));
+        assert!(!kernel_can_read(end));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn guard_page_start() {
+        let mut buffer = GuardedBuffer::new(0x2000, GuardPage::Start).unwrap();
+        buffer.fill(0x41);
+
+        let start = buffer.as_ptr();
+        assert_eq!(0, start as usize % sys::page_size());
+        assert!(kernel_can_read(start));
+        assert!(!kernel_can_read(unsafe { start.sub(1) }));
+    }
+}
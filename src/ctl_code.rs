@@ -0,0 +1,380 @@
+use crate::expr::{self, Number};
+use serde::Deserialize;
+use serde::de::{self, Deserializer, IntoDeserializer, MapAccess, Visitor};
+use std::fmt;
+
+/// An IOCTL code split into the four parts that the `CTL_CODE` macro packs together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CtlCode {
+    pub device_type: u16,
+    /// Function number, which must fit in 12 bits
+    pub function: u16,
+    pub method: Method,
+    pub access: Access,
+}
+
+/// How the I/O manager passes the buffers to the driver
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    #[default]
+    Buffered,
+    InDirect,
+    OutDirect,
+    /// The driver is given the caller's buffer pointers directly
+    Neither,
+}
+
+/// Access that the handle must have been opened with to send the IOCTL
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    #[default]
+    Any,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// A Linux ioctl request number split into the four parts that the `_IOC` macro packs
+/// together, as used by `_IO`, `_IOW`, `_IOR` and `_IOWR`. This is the layout used by most
+/// architectures, including x86 and arm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IocCode {
+    pub dir: Direction,
+    /// Magic number identifying the driver, usually a character, e.g. `'T'` for ttys
+    pub ioc_type: u8,
+    pub nr: u8,
+    /// Size of the argument, which must fit in 14 bits
+    pub size: u16,
+}
+
+/// Which way the argument of a Linux ioctl is copied, from the point of view of the caller
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// `_IO`: no argument
+    None,
+    /// `_IOW`: the driver reads the argument
+    Write,
+    /// `_IOR`: the driver writes the argument
+    Read,
+    /// `_IOWR`: the driver reads and then writes the argument
+    ReadWrite,
+}
+
+impl CtlCode {
+    /// Largest function number that fits in a code
+    pub const MAX_FUNCTION: u16 = 0xFFF;
+
+    /// Packs the parts into a code, as `CTL_CODE` does
+    pub fn encode(self) -> Result<u32, &'static str> {
+        if self.function > Self::MAX_FUNCTION {
+            return Err("function must fit in 12 bits");
+        }
+
+        Ok((u32::from(self.device_type) << 16)
+            | ((self.access as u32) << 14)
+            | (u32::from(self.function) << 2)
+            | self.method as u32)
+    }
+
+    /// Splits a code into its parts. Every code can be decoded, so this never fails.
+    pub fn decode(code: u32) -> CtlCode {
+        let method = match code & 0x3 {
+            0 => Method::Buffered,
+            1 => Method::InDirect,
+            2 => Method::OutDirect,
+            _ => Method::Neither,
+        };
+
+        let access = match (code >> 14) & 0x3 {
+            0 => Access::Any,
+            1 => Access::Read,
+            2 => Access::Write,
+            _ => Access::ReadWrite,
+        };
+
+        CtlCode {
+            device_type: (code >> 16) as u16,
+            function: ((code >> 2) & 0xFFF) as u16,
+            method,
+            access,
+        }
+    }
+}
+
+impl IocCode {
+    /// Largest argument size that fits in a request number
+    pub const MAX_SIZE: u16 = 0x3FFF;
+
+    /// Packs the parts into a request number, as `_IOC` does
+    pub fn encode(self) -> Result<u32, &'static str> {
+        if self.size > Self::MAX_SIZE {
+            return Err("size must fit in 14 bits");
+        }
+
+        Ok(((self.dir as u32) << 30)
+            | (u32::from(self.size) << 16)
+            | (u32::from(self.ioc_type) << 8)
+            | u32::from(self.nr))
+    }
+
+    /// Splits a request number into its parts. Older request numbers, such as most of the
+    /// tty ones, were assigned before the direction and size were packed in, so decode with a
+    /// direction of `None` and a size of 0.
+    pub fn decode(code: u32) -> IocCode {
+        let dir = match code >> 30 {
+            0 => Direction::None,
+            1 => Direction::Write,
+            2 => Direction::Read,
+            _ => Direction::ReadWrite,
+        };
+
+        IocCode {
+            dir,
+            ioc_type: (code >> 8) as u8,
+            nr: code as u8,
+            size: ((code >> 16) & 0x3FFF) as u16,
+        }
+    }
+}
+
+impl fmt::Display for CtlCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "device_type=0x{:X} function=0x{:X} {} {}",
+            self.device_type, self.function, self.method, self.access
+        )
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Method::Buffered => "METHOD_BUFFERED",
+            Method::InDirect => "METHOD_IN_DIRECT",
+            Method::OutDirect => "METHOD_OUT_DIRECT",
+            Method::Neither => "METHOD_NEITHER",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Access::Any => "FILE_ANY_ACCESS",
+            Access::Read => "FILE_READ_ACCESS",
+            Access::Write => "FILE_WRITE_ACCESS",
+            Access::ReadWrite => "FILE_READ_ACCESS | FILE_WRITE_ACCESS",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+impl fmt::Display for IocCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} type=", self.dir)?;
+
+        match self.ioc_type {
+            ioc_type if ioc_type.is_ascii_graphic() => write!(f, "'{}'", ioc_type as char)?,
+            ioc_type => write!(f, "0x{ioc_type:X}")?,
+        }
+
+        write!(f, " nr=0x{:X} size=0x{:X}", self.nr, self.size)
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Direction::None => "_IO",
+            Direction::Write => "_IOW",
+            Direction::Read => "_IOR",
+            Direction::ReadWrite => "_IOWR",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// A table of either `CtlCode` or `IocCode` parts, told apart by whether `dir` is given
+#[derive(Deserialize)]
+struct CodeTable {
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    device_type: Option<u16>,
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    function: Option<u16>,
+    method: Option<Method>,
+    access: Option<Access>,
+    dir: Option<Direction>,
+    #[serde(
+        rename = "type",
+        default,
+        deserialize_with = "expr::deserialize_option_number"
+    )]
+    ioc_type: Option<u8>,
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    nr: Option<u8>,
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    size: Option<u16>,
+}
+
+impl CodeTable {
+    fn encode(self) -> Result<u32, &'static str> {
+        let Some(dir) = self.dir else {
+            if self.ioc_type.is_some() || self.nr.is_some() || self.size.is_some() {
+                return Err("type, nr and size are only used along with dir");
+            }
+
+            return CtlCode {
+                device_type: self.device_type.ok_or("missing field `device_type`")?,
+                function: self.function.ok_or("missing field `function`")?,
+                method: self.method.unwrap_or_default(),
+                access: self.access.unwrap_or_default(),
+            }
+            .encode();
+        };
+
+        if self.device_type.is_some()
+            || self.function.is_some()
+            || self.method.is_some()
+            || self.access.is_some()
+        {
+            return Err("device_type, function, method and access can't be used along with dir");
+        }
+
+        IocCode {
+            dir,
+            ioc_type: self.ioc_type.ok_or("missing field `type`")?,
+            nr: self.nr.ok_or("missing field `nr`")?,
+            size: self.size.unwrap_or(0),
+        }
+        .encode()
+    }
+}
+
+/// For `deserialize_with` on an IOCTL code, which can be given either as an integer (or
+/// expression), as a table of `CtlCode` parts, or as a table of `IocCode` parts for Linux
+/// devices
+pub fn deserialize_code<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct CodeVisitor;
+
+    impl<'de> Visitor<'de> for CodeVisitor {
+        type Value = u32;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "an IOCTL code, or a table of either device_type, function, method and access, or dir, type, nr and size"
+            )
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<u32, E> {
+            Ok(Number::deserialize(value.into_deserializer())?.0)
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<u32, E> {
+            Ok(Number::deserialize(value.into_deserializer())?.0)
+        }
+
+        fn visit_str<E: de::Error>(self, expression: &str) -> Result<u32, E> {
+            Ok(Number::deserialize(expression.into_deserializer())?.0)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<u32, A::Error> {
+            CodeTable::deserialize(de::value::MapAccessDeserializer::new(map))?
+                .encode()
+                .map_err(de::Error::custom)
+        }
+    }
+
+    deserializer.deserialize_any(CodeVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_ctl_code() {
+        let ctl_code = CtlCode {
+            device_type: 0x22,
+            function: 0x800,
+            method: Method::Buffered,
+            access: Access::Any,
+        };
+        assert_eq!(Ok(0x222000), ctl_code.encode());
+
+        let ctl_code = CtlCode {
+            device_type: 0xFFFF,
+            function: 0xFFF,
+            method: Method::Neither,
+            access: Access::ReadWrite,
+        };
+        assert_eq!(Ok(0xFFFFFFFF), ctl_code.encode());
+
+        let ctl_code = CtlCode {
+            function: 0x1000,
+            ..ctl_code
+        };
+        assert!(ctl_code.encode().is_err());
+    }
+
+    #[test]
+    fn decode_ctl_code() {
+        let ctl_code = CtlCode::decode(0x22E01B);
+        assert_eq!(
+            CtlCode {
+                device_type: 0x22,
+                function: 0x806,
+                method: Method::Neither,
+                access: Access::ReadWrite,
+            },
+            ctl_code
+        );
+        assert_eq!(Ok(0x22E01B), ctl_code.encode());
+        assert_eq!(
+            "device_type=0x22 function=0x806 METHOD_NEITHER FILE_READ_ACCESS | FILE_WRITE_ACCESS",
+            ctl_code.to_string()
+        );
+
+        assert_eq!(Method::InDirect, CtlCode::decode(0x222005).method);
+        assert_eq!(Access::Read, CtlCode::decode(0x226002).access);
+        assert_eq!(Method::OutDirect, CtlCode::decode(0x226002).method);
+    }
+
+    #[test]
+    fn encode_and_decode_ioc_code() {
+        // TIOCGPTN is _IOR('T', 0x30, unsigned int)
+        let ioc_code = IocCode {
+            dir: Direction::Read,
+            ioc_type: b'T',
+            nr: 0x30,
+            size: 4,
+        };
+        assert_eq!(Ok(0x80045430), ioc_code.encode());
+        assert_eq!(ioc_code, IocCode::decode(0x80045430));
+        assert_eq!("_IOR type='T' nr=0x30 size=0x4", ioc_code.to_string());
+
+        // TIOCSPTLCK is _IOW('T', 0x31, int)
+        assert_eq!(Direction::Write, IocCode::decode(0x40045431).dir);
+        assert_eq!(
+            "_IO type=0x0 nr=0x1 size=0x0",
+            IocCode::decode(0x1).to_string()
+        );
+
+        let ioc_code = IocCode {
+            size: 0x4000,
+            ..ioc_code
+        };
+        assert!(ioc_code.encode().is_err());
+    }
+}
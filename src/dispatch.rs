@@ -1,142 +1,386 @@
 use crate::Ioctl;
-use crate::thread;
-use crate::win_helpers::{open_device_handle, send_device_io_control};
+use crate::ctl_code::Method;
+use crate::error::{BufferError, DispatchError};
+use crate::open_options::OpenOptions;
+use crate::report::Reporter;
+use crate::transport::{DeviceTransport, IoctlStatus, NativeTransport};
 use basic_mutator::{EmptyDatabase, Mutator};
-use windows::Win32::Foundation::HANDLE;
+use std::io;
+use std::time::{Duration, Instant};
 
 /// Describes a struct that can take some form of input and send it to a destination.
 /// Current implementation will cover dispatchers for IOCTLs and Filter Communication Port
 /// messages, but could also extend to other generic OS functionality such as spawning processes.
 ///
+/// Dispatchers don't print anything. The outcome of each IOCTL is handed to `reporter` as
+/// soon as it completes, so that dispatchers which never finish, such as fuzzers, can still
+/// report what they find. An error is returned when the dispatcher can't carry on.
+///
 /// Also allows for mocking out calls in tests where we can't actually communicate with
 /// a driver.
 pub trait Dispatcher {
-    fn dispatch(&self) -> windows::core::Result<()>;
+    fn dispatch(&self, reporter: &dyn Reporter) -> Result<(), DispatchError>;
 }
 
-/// Dispatcher used to call DeviceIoControl in order to send an IRP to a specified
-/// IOCTL for a driver.
-pub struct SingleIoctlDispatcher<'a> {
+/// What happened when an IOCTL was sent
+#[derive(Debug)]
+pub struct DispatchOutcome {
+    /// Name of the IOCTL that was sent
+    pub ioctl: String,
     pub device_name: String,
-    pub ioctl: &'a Ioctl,
+    pub code: u32,
+    /// Access that the device handle was opened with
+    pub desired_access: u32,
+    /// Error opening the device, in which case nothing was sent. Only the access matrix
+    /// carries on past a device that can't be opened, the other dispatchers return the error.
+    pub open_error: Option<io::Error>,
+    /// Contents of the input buffer that was sent, or None if it was NULL
+    pub input: Option<Vec<u8>>,
+    /// Contents of the whole output buffer once the IOCTL completed, or None if it was NULL.
+    /// Only the first `status.bytes_returned` bytes were written by the driver.
+    pub output: Option<Vec<u8>>,
+    /// Size of the output buffer that the driver was told about
+    pub output_buffer_size: usize,
+    /// Bytes returned and the error, if any, that the IOCTL completed with
+    pub status: IoctlStatus,
+    /// How long the IOCTL took to complete
+    pub duration: Duration,
+    /// Possible kernel addresses in the returned output of a successful IOCTL, as
+    /// (offset, address)
+    pub leaks: Vec<(usize, u64)>,
+    /// Whether the driver faulted on one of our guard pages, so accessed memory outside of
+    /// the buffers it was given
+    pub guard_page_fault: bool,
+    /// Entries in the IOCTL's buffers that write to the same bytes. These are the same every
+    /// time the buffers are built, so only the first outcome of a dispatch has them.
+    pub overlaps: Vec<BufferError>,
 }
 
-impl<'a> Dispatcher for SingleIoctlDispatcher<'a> {
-    fn dispatch(&self) -> windows::core::Result<()> {
-        println!("Sending {} to {}", self.ioctl.name, self.device_name);
+impl DispatchOutcome {
+    /// Describes an IOCTL that was never sent, because the device couldn't be opened
+    fn open_failed(ioctl: &Ioctl, options: &OpenOptions, error: io::Error) -> DispatchOutcome {
+        DispatchOutcome {
+            ioctl: ioctl.name.clone(),
+            device_name: ioctl.device_name().to_string(),
+            code: ioctl.code,
+            desired_access: options.desired_access_or_default(),
+            open_error: Some(error),
+            input: None,
+            output: None,
+            output_buffer_size: ioctl.declared_output_buffer_size(),
+            status: IoctlStatus::default(),
+            duration: Duration::ZERO,
+            leaks: Vec::new(),
+            guard_page_fault: false,
+            overlaps: Vec::new(),
+        }
+    }
 
-        let device_handle: HANDLE = open_device_handle(&self.device_name, self.ioctl.overlapped)?;
+    /// Whether the IOCTL failed, or couldn't be sent because the device couldn't be opened
+    pub fn failed(&self) -> bool {
+        self.open_error.is_some() || !self.status.succeeded()
+    }
 
-        let input_buffer = self.ioctl.build_input_buffer().unwrap();
+    /// The part of the output buffer that the driver reported writing
+    pub fn returned_output(&self) -> &[u8] {
+        let output = self.output.as_deref().unwrap_or_default();
 
-        let output_buffer = send_device_io_control(
-            device_handle,
-            self.ioctl.code,
-            &input_buffer,
-            self.ioctl.input_buffer_size,
-            self.ioctl.output_buffer_size,
-        )?;
+        &output[..self.status.bytes_returned.min(output.len())]
+    }
 
-        println!("DeviceIoControl called successfully.");
+    /// The rest of the output buffer, which the driver didn't report writing, so still
+    /// holds whatever it was initialised with unless the driver under-reported
+    pub fn rest_of_output(&self) -> &[u8] {
+        let output = self.output.as_deref().unwrap_or_default();
 
-        unsafe {
-            windows::Win32::Foundation::CloseHandle(device_handle)?;
-        }
+        &output[self.status.bytes_returned.min(output.len())..]
+    }
 
-        println!("Device handle closed successfully.");
+    /// Number of bytes allocated for the output buffer, which can be fewer than the driver
+    /// was told about
+    pub fn allocated_output_size(&self) -> usize {
+        self.output.as_ref().map_or(0, Vec::len)
+    }
 
-        if output_buffer.len() > 0 {
-            println!("Output:\n{:X?}\n", output_buffer);
+    /// Whether the driver reported returning more bytes than the output buffer holds, as
+    /// allocated or as declared to the driver, which means it wrote, or the I/O manager
+    /// copied, past the end of it. Drivers can report this along with an error status too.
+    pub fn overreported_bytes_returned(&self) -> bool {
+        self.status.bytes_returned > self.output_buffer_size.min(self.allocated_output_size())
+    }
 
-            if let Some(possible_info_leaks) = check_info_leaks(&output_buffer) {
-                for leak in possible_info_leaks {
-                    println!("Possible leak at {}: {}", leak.0, leak.1);
-                }
-            }
-        } else {
-            println!("No output buffer received");
-        }
+    /// Whether the outcome points at a bug in the driver, which is a guard page fault, a
+    /// possible info leak or more bytes returned than the output buffer holds
+    pub fn is_finding(&self) -> bool {
+        self.guard_page_fault || !self.leaks.is_empty() || self.overreported_bytes_returned()
+    }
+}
+
+/// Dispatcher used to call DeviceIoControl in order to send an IRP to a specified
+/// IOCTL for a driver.
+pub struct SingleIoctlDispatcher<'a, T: DeviceTransport = NativeTransport> {
+    pub ioctl: &'a Ioctl,
+    pub transport: T,
+}
+
+impl<'a, T: DeviceTransport> Dispatcher for SingleIoctlDispatcher<'a, T> {
+    fn dispatch(&self, reporter: &dyn Reporter) -> Result<(), DispatchError> {
+        // Build the buffers before opening the device, so that there is no handle to leak if
+        // they can't be built
+        let mut buffers = self.ioctl.prepare_buffers()?;
+
+        let device_handle = self
+            .transport
+            .open(
+                self.ioctl.device_name(),
+                self.ioctl.open_options(),
+                self.ioctl.overlapped,
+            )
+            .map_err(DispatchError::Device)?;
+
+        let outcome = send_ioctl(
+            &self.transport,
+            &device_handle,
+            self.ioctl,
+            self.ioctl.open_options(),
+            buffers.input.as_deref(),
+            self.ioctl.declared_input_buffer_size(),
+            buffers.output.as_deref_mut(),
+        );
+
+        // Close the handle before looking at the result, so that it isn't leaked on failure
+        self.transport
+            .close(device_handle)
+            .map_err(DispatchError::Device)?;
+
+        let mut outcome = outcome.map_err(DispatchError::Device)?;
+        outcome.overlaps = std::mem::take(&mut buffers.overlaps);
+        reporter.report(outcome);
 
         Ok(())
     }
 }
 
 #[derive(Clone)]
-pub struct FuzzIoctlDispatcher {
-    pub device_name: String,
+pub struct FuzzIoctlDispatcher<T: DeviceTransport = NativeTransport> {
     pub ioctl: Ioctl,
+    pub transport: T,
 }
 
-impl Dispatcher for FuzzIoctlDispatcher {
-    fn dispatch(&self) -> windows::core::Result<()> {
-        println!(
-            "Starting to fuzz {} with {} in thread {:?}",
-            self.device_name,
-            self.ioctl.name,
-            thread::current().id()
-        );
-
-        let seed_input_buffer = self.ioctl.build_input_buffer().unwrap();
+impl<T: DeviceTransport> Dispatcher for FuzzIoctlDispatcher<T> {
+    fn dispatch(&self, reporter: &dyn Reporter) -> Result<(), DispatchError> {
+        // The auxiliary buffers are shared by every iteration, so that the pointers to them
+        // in the seed input stay valid
+        let mut seed_buffers = self.ioctl.prepare_buffers()?;
+        let mut overlaps = std::mem::take(&mut seed_buffers.overlaps);
+        let seed_input_buffer = seed_buffers.input.as_deref();
 
         let mut mutator = Mutator::new()
             .seed(0x50BA5EDF001) // TODO: Provide this somewhere
-            .max_input_size(self.ioctl.input_buffer_size)
+            .max_input_size(self.ioctl.allocated_input_buffer_size())
             .printable(false);
 
         loop {
             mutator.input.clear();
-            mutator.input.extend_from_slice(&seed_input_buffer);
 
-            mutator.mutate(4, &EmptyDatabase);
-
-            let device_handle: HANDLE =
-                open_device_handle(&self.device_name, self.ioctl.overlapped)?;
+            // A NULL input buffer stays NULL, so there is nothing to mutate
+            if let Some(seed_input_buffer) = seed_input_buffer {
+                mutator.input.extend_from_slice(seed_input_buffer);
+                mutator.mutate(4, &EmptyDatabase);
+            }
 
-            let output_buffer = send_device_io_control(
-                device_handle,
-                self.ioctl.code,
-                &mutator.input,
-                self.ioctl.input_buffer_size,
-                self.ioctl.output_buffer_size,
+            // Copy the mutated input into a fresh allocation each time, so that it stays flush
+            // against any guard page as its length changes
+            let (input_buffer, input_buffer_size) = match seed_input_buffer {
+                Some(_) => (
+                    Some(self.ioctl.allocate_buffer(&mutator.input)?),
+                    self.ioctl.fuzzed_input_buffer_size(mutator.input.len()),
+                ),
+                None => (None, self.ioctl.declared_input_buffer_size()),
+            };
+            let mut output_buffer = self.ioctl.allocate_output_buffer()?;
+
+            let device_handle = self
+                .transport
+                .open(
+                    self.ioctl.device_name(),
+                    self.ioctl.open_options(),
+                    self.ioctl.overlapped,
+                )
+                .map_err(DispatchError::Device)?;
+
+            let outcome = send_ioctl(
+                &self.transport,
+                &device_handle,
+                &self.ioctl,
+                self.ioctl.open_options(),
+                input_buffer.as_deref(),
+                input_buffer_size,
+                output_buffer.as_deref_mut(),
             );
 
-            match output_buffer {
-                Ok(output_buffer) => {
-                    if output_buffer.len() > 0 {
-                        if let Some(possible_info_leaks) = check_info_leaks(&output_buffer) {
-                            for leak in possible_info_leaks {
-                                println!("Possible leak at {}: {}", leak.0, leak.1);
-                            }
-                        }
-                    }
-                }
-                Err(_) => (),
+            // An IOCTL that couldn't be sent at all is skipped. If the driver has gone
+            // down with it, opening the device fails on the next iteration.
+            if let Ok(mut outcome) = outcome {
+                outcome.overlaps = std::mem::take(&mut overlaps);
+                reporter.report(outcome);
             }
 
-            unsafe {
-                windows::Win32::Foundation::CloseHandle(device_handle)?;
+            self.transport
+                .close(device_handle)
+                .map_err(DispatchError::Device)?;
+        }
+    }
+}
+
+/// Dispatcher that sends an IOCTL once for each access mask in `access_masks`, each time
+/// through a new handle opened with only that access, and reports each outcome. Successes
+/// through handles without read or write access point at an IOCTL that doesn't check the
+/// access of its caller.
+pub struct AccessMatrixDispatcher<'a, T: DeviceTransport = NativeTransport> {
+    pub ioctl: &'a Ioctl,
+    pub access_masks: Vec<u32>,
+    pub transport: T,
+}
+
+impl<'a, T: DeviceTransport> Dispatcher for AccessMatrixDispatcher<'a, T> {
+    fn dispatch(&self, reporter: &dyn Reporter) -> Result<(), DispatchError> {
+        let mut overlaps_reported = false;
+
+        for &access_mask in &self.access_masks {
+            let options = OpenOptions {
+                desired_access: Some(access_mask),
+                ..self.ioctl.open_options().clone()
+            };
+
+            let mut outcome =
+                match self
+                    .transport
+                    .open(self.ioctl.device_name(), &options, self.ioctl.overlapped)
+                {
+                    Ok(device_handle) => {
+                        let outcome = self.send_with_handle(&device_handle, &options);
+
+                        self.transport
+                            .close(device_handle)
+                            .map_err(DispatchError::Device)?;
+
+                        outcome?
+                    }
+                    Err(e) => DispatchOutcome::open_failed(self.ioctl, &options, e),
+                };
+
+            if overlaps_reported {
+                outcome.overlaps.clear();
             }
+            overlaps_reported |= outcome.open_error.is_none();
+
+            reporter.report(outcome);
         }
 
         Ok(())
     }
 }
 
+impl<T: DeviceTransport> AccessMatrixDispatcher<'_, T> {
+    /// Sends the IOCTL through a handle opened with `options`
+    fn send_with_handle(
+        &self,
+        device_handle: &T::Handle,
+        options: &OpenOptions,
+    ) -> Result<DispatchOutcome, DispatchError> {
+        let mut buffers = self.ioctl.prepare_buffers()?;
+
+        let mut outcome = send_ioctl(
+            &self.transport,
+            device_handle,
+            self.ioctl,
+            options,
+            buffers.input.as_deref(),
+            self.ioctl.declared_input_buffer_size(),
+            buffers.output.as_deref_mut(),
+        )
+        .map_err(DispatchError::Device)?;
+        outcome.overlaps = buffers.overlaps;
+
+        Ok(outcome)
+    }
+}
+
 // Dispatcher helpers
 
+/// Sends an IOCTL through an open device handle, telling the driver that the input buffer
+/// is `input_buffer_size` bytes, and describes what happened. An error is only returned when
+/// the IOCTL couldn't be sent at all.
+fn send_ioctl<T: DeviceTransport>(
+    transport: &T,
+    device_handle: &T::Handle,
+    ioctl: &Ioctl,
+    options: &OpenOptions,
+    input_buffer: Option<&[u8]>,
+    input_buffer_size: usize,
+    mut output_buffer: Option<&mut [u8]>,
+) -> io::Result<DispatchOutcome> {
+    let start = Instant::now();
+    let status = transport.ioctl(
+        device_handle,
+        ioctl.code,
+        input_buffer,
+        input_buffer_size,
+        output_buffer.as_deref_mut(),
+        ioctl.declared_output_buffer_size(),
+    )?;
+    let duration = start.elapsed();
+
+    let mut outcome = DispatchOutcome {
+        ioctl: ioctl.name.clone(),
+        device_name: ioctl.device_name().to_string(),
+        code: ioctl.code,
+        desired_access: options.desired_access_or_default(),
+        open_error: None,
+        input: input_buffer.map(<[u8]>::to_vec),
+        output: output_buffer.map(|output_buffer| output_buffer.to_vec()),
+        output_buffer_size: ioctl.declared_output_buffer_size(),
+        guard_page_fault: is_guard_page_fault(ioctl, &status),
+        status,
+        duration,
+        leaks: Vec::new(),
+        overlaps: Vec::new(),
+    };
+
+    // Only look for leaks in what the driver wrote, as the rest of the buffer is ours
+    if outcome.status.succeeded() {
+        outcome.leaks = check_info_leaks(outcome.returned_output()).unwrap_or_default();
+    }
+
+    Ok(outcome)
+}
+
+/// Checks whether a failed IOCTL was the driver touching one of our guard pages. With
+/// METHOD_BUFFERED, a declared size larger than the allocated buffer makes the I/O manager
+/// fault while copying the buffer, before the driver is ever called, so that isn't counted.
+fn is_guard_page_fault(ioctl: &Ioctl, status: &IoctlStatus) -> bool {
+    let faults_while_copying = !ioctl.is_linux_device()
+        && ioctl.ctl_code().method == Method::Buffered
+        && (ioctl.declared_input_buffer_size() > ioctl.allocated_input_buffer_size()
+            || ioctl.declared_output_buffer_size() > ioctl.allocated_output_buffer_size());
+
+    ioctl.uses_guard_page() && status.is_access_violation() && !faults_while_copying
+}
+
 /// Iterates through a buffer in pointer-sized chunks, and checks to see whether
 /// value falls within kernel address range. Returns a vec of (offset, leaked_addr) tuples
 /// This iterates through the buffer 2-bytes at a time, which is a crude way of increasing the
 /// likelihood of catching things at weird offsets, but reducing some of the false positives from
 /// a 1-byte sliding window
-fn check_info_leaks(buffer: &Vec<u8>) -> Option<Vec<(usize, u64)>> {
+fn check_info_leaks(buffer: &[u8]) -> Option<Vec<(usize, u64)>> {
     const KERNEL_ADDR_MIN: u64 = 0xFFFF800000000000;
     const POINTER_SIZE: usize = 8;
 
     let mut found_addresses = Vec::new();
 
     let mut i = 0;
-    while i <= buffer.len().saturating_sub(POINTER_SIZE) {
+    while i + POINTER_SIZE <= buffer.len() {
         let curr_offset = &buffer[i..i + POINTER_SIZE];
         let potential_addr = u64::from_ne_bytes(curr_offset.try_into().unwrap());
 
@@ -148,7 +392,7 @@ fn check_info_leaks(buffer: &Vec<u8>) -> Option<Vec<(usize, u64)>> {
         }
     }
 
-    if found_addresses.len() == 0 {
+    if found_addresses.is_empty() {
         return None;
     }
 
@@ -186,4 +430,90 @@ mod tests {
             panic!("No info leaks found")
         }
     }
+
+    #[test]
+    fn outcome_splits_output_at_bytes_returned() {
+        let mut outcome = DispatchOutcome {
+            ioctl: "IOCTL_TEST".to_string(),
+            device_name: "\\\\.\\Test".to_string(),
+            code: 0x222000,
+            desired_access: 0xC0000000,
+            open_error: None,
+            input: None,
+            output: Some(vec![1, 2, 3, 4, 0, 0]),
+            output_buffer_size: 6,
+            status: IoctlStatus {
+                bytes_returned: 4,
+                status: 0,
+            },
+            duration: Duration::ZERO,
+            leaks: Vec::new(),
+            guard_page_fault: false,
+            overlaps: Vec::new(),
+        };
+
+        assert_eq!([1, 2, 3, 4], outcome.returned_output());
+        assert_eq!([0, 0], outcome.rest_of_output());
+        assert!(!outcome.overreported_bytes_returned());
+        assert!(!outcome.is_finding());
+
+        // Reporting more than the buffer holds is a finding, and the whole buffer is returned
+        outcome.status.bytes_returned = 0x100;
+        assert_eq!([1, 2, 3, 4, 0, 0], outcome.returned_output());
+        assert!(outcome.rest_of_output().is_empty());
+        assert!(outcome.overreported_bytes_returned());
+        assert!(outcome.is_finding());
+
+        // Declaring more than was allocated doesn't hide an over-report of the allocation
+        outcome.output_buffer_size = 0x10;
+        outcome.status.bytes_returned = 0x8;
+        assert_eq!(6, outcome.allocated_output_size());
+        assert!(outcome.overreported_bytes_returned());
+
+        // Nor does a failure status
+        outcome.status.status = 234;
+        assert!(outcome.overreported_bytes_returned());
+        assert!(outcome.is_finding());
+
+        // A NULL output buffer has nothing in either part, and holds nothing at all
+        outcome.output = None;
+        outcome.status.bytes_returned = 0x1;
+        assert!(outcome.returned_output().is_empty());
+        assert!(outcome.rest_of_output().is_empty());
+        assert!(outcome.overreported_bytes_returned());
+    }
+
+    #[test]
+    fn guard_page_fault_ignores_buffered_copies() {
+        let mut ioctl: Ioctl = toml::from_str(
+            r#"
+            name = "IOCTL_TEST"
+            code = 0x222000
+            input_buffer_size = 0x10
+            output_buffer_size = 0x10
+            guard_page = "end"
+            "#,
+        )
+        .unwrap();
+        let status = IoctlStatus {
+            bytes_returned: 0,
+            status: crate::transport::ACCESS_VIOLATION,
+        };
+
+        assert!(is_guard_page_fault(&ioctl, &status));
+
+        // The I/O manager faults copying the METHOD_BUFFERED input, not the driver
+        ioctl.declared_input_buffer_size = Some(0x1000);
+        assert!(!is_guard_page_fault(&ioctl, &status));
+
+        // Whereas METHOD_NEITHER hands the driver the buffer itself
+        ioctl.code = 0x222003;
+        assert!(is_guard_page_fault(&ioctl, &status));
+    }
+
+    #[test]
+    fn check_info_leaks_short_buffer() {
+        assert_eq!(None, check_info_leaks(&[0xFF; 4]));
+        assert_eq!(None, check_info_leaks(&[]));
+    }
 }
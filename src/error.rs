@@ -0,0 +1,119 @@
+use crate::Overlap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Error building an IOCTL's input buffer, with enough context to find the offending entry
+/// in the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferError {
+    /// Name of the IOCTL whose buffer was being built
+    pub ioctl: String,
+    /// Name of the auxiliary buffer being built, or None for the input buffer
+    pub aux_buffer: Option<String>,
+    /// Index of the `input_buffer_content` entry at fault
+    pub entry: usize,
+    /// Config file that the IOCTL was declared in, if known
+    pub file: Option<Box<Path>>,
+    /// Line of the config file that the entry is declared on, if known
+    pub line: Option<usize>,
+    pub kind: BufferErrorKind,
+}
+
+/// What went wrong with an input buffer entry
+#[derive(Debug, Clone, PartialEq)]
+pub enum BufferErrorKind {
+    /// The entry writes past the end of the input buffer
+    OutOfBounds {
+        offset: usize,
+        size: usize,
+        buffer_size: usize,
+    },
+    /// The entry writes to the same bytes as another entry, and the IOCTL is in strict mode
+    Overlap(Box<Overlap>),
+    /// The entry could not be laid out, e.g. a value does not fit or a name is unknown
+    Invalid(&'static str),
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.aux_buffer {
+            Some(aux_buffer) => write!(f, "{}: aux buffer {aux_buffer}", self.ioctl)?,
+            None => write!(f, "{}: input buffer", self.ioctl)?,
+        }
+
+        write!(f, " entry {}", self.entry)?;
+
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, " ({} line {line})", file.display())?,
+            (Some(file), None) => write!(f, " ({})", file.display())?,
+            (None, Some(line)) => write!(f, " (line {line})")?,
+            (None, None) => (),
+        }
+
+        write!(f, ": {}", self.kind)
+    }
+}
+
+impl fmt::Display for BufferErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BufferErrorKind::OutOfBounds {
+                offset,
+                size,
+                buffer_size,
+            } => write!(
+                f,
+                "0x{size:X} bytes at offset 0x{offset:X} do not fit in the 0x{buffer_size:X} byte input buffer"
+            ),
+            BufferErrorKind::Overlap(overlap) => write!(f, "{overlap}"),
+            BufferErrorKind::Invalid(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl From<&'static str> for BufferErrorKind {
+    fn from(reason: &'static str) -> Self {
+        BufferErrorKind::Invalid(reason)
+    }
+}
+
+impl Error for BufferError {}
+
+/// Error returned by a Dispatcher, either before anything is sent or while sending it
+#[derive(Debug)]
+pub enum DispatchError {
+    /// The input buffer could not be built from the config
+    Buffer(BufferError),
+    /// A buffer to pass to the device could not be allocated
+    Allocation(io::Error),
+    /// Opening or sending to the device failed
+    Device(io::Error),
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DispatchError::Buffer(e) => write!(f, "{e}"),
+            DispatchError::Allocation(e) => write!(f, "Failed to allocate buffer: {e}"),
+            DispatchError::Device(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for DispatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DispatchError::Buffer(e) => Some(e),
+            DispatchError::Allocation(e) => Some(e),
+            DispatchError::Device(e) => Some(e),
+        }
+    }
+}
+
+impl From<BufferError> for DispatchError {
+    fn from(e: BufferError) -> Self {
+        DispatchError::Buffer(e)
+    }
+}
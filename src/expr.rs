@@ -0,0 +1,490 @@
+use crate::open_options;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+thread_local! {
+    /// Values of the config's `[vars]` table, available to expressions while the config is
+    /// being deserialized
+    static VARS: RefCell<HashMap<String, i128>> = RefCell::new(HashMap::new());
+}
+
+/// Error evaluating an integer expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprError {
+    /// The expression refers to a name that isn't in `[vars]` or a known constant
+    UndefinedName(String),
+    /// An intermediate or final value is too large
+    Overflow,
+    DivideByZero,
+    /// A `[vars]` entry refers to itself, directly or through other vars
+    Cycle(String),
+    /// The expression could not be parsed
+    Syntax(&'static str),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::UndefinedName(name) => write!(f, "undefined name `{name}`"),
+            ExprError::Overflow => write!(f, "arithmetic overflow"),
+            ExprError::DivideByZero => write!(f, "division by zero"),
+            ExprError::Cycle(name) => write!(f, "`{name}` is defined in terms of itself"),
+            ExprError::Syntax(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl Error for ExprError {}
+
+/// Evaluates an integer expression, looking up any names with `lookup`. Supports decimal,
+/// `0x`, `0o` and `0b` literals, parentheses, and the usual C operators and precedence:
+/// unary `-` and `~`, then `*` `/` `%`, `+` `-`, `<<` `>>`, `&`, `^`, `|`.
+pub fn evaluate(
+    expression: &str,
+    lookup: &mut dyn FnMut(&str) -> Result<i128, ExprError>,
+) -> Result<i128, ExprError> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+        lookup,
+    };
+
+    let value = parser.binary(0)?;
+
+    if parser.position != parser.tokens.len() {
+        return Err(ExprError::Syntax("unexpected token after expression"));
+    }
+
+    Ok(value)
+}
+
+/// Evaluates the raw values of the `[vars]` table, which can refer to each other in any
+/// order.
+pub fn evaluate_vars(
+    raw_vars: &HashMap<String, RawNumber>,
+) -> Result<HashMap<String, i128>, Box<dyn Error>> {
+    let mut vars = HashMap::new();
+
+    for name in raw_vars.keys() {
+        evaluate_var(name, raw_vars, &mut vars, &mut Vec::new())
+            .map_err(|e| format!("error evaluating vars.{name}: {e}"))?;
+    }
+
+    Ok(vars)
+}
+
+/// Evaluates a single var, first evaluating any vars it refers to. `visiting` holds the
+/// vars currently being evaluated, to catch cycles.
+fn evaluate_var(
+    name: &str,
+    raw_vars: &HashMap<String, RawNumber>,
+    vars: &mut HashMap<String, i128>,
+    visiting: &mut Vec<String>,
+) -> Result<i128, ExprError> {
+    if let Some(value) = vars.get(name) {
+        return Ok(*value);
+    }
+
+    if visiting.iter().any(|visited| visited == name) {
+        return Err(ExprError::Cycle(name.to_string()));
+    }
+
+    let value = match raw_vars.get(name) {
+        Some(RawNumber::Value(value)) => *value,
+        Some(RawNumber::Expression(expression)) => {
+            visiting.push(name.to_string());
+            let value = evaluate(expression, &mut |referenced| {
+                evaluate_var(var_name(referenced), raw_vars, vars, visiting)
+            })?;
+            visiting.pop();
+
+            value
+        }
+        None => {
+            return open_options::constant(name)
+                .map(i128::from)
+                .ok_or_else(|| ExprError::UndefinedName(name.to_string()));
+        }
+    };
+
+    vars.insert(name.to_string(), value);
+
+    Ok(value)
+}
+
+/// Vars can be referred to either by name or as `vars.<name>`
+fn var_name(name: &str) -> &str {
+    name.strip_prefix("vars.").unwrap_or(name)
+}
+
+/// Makes the evaluated vars available to expressions until the returned guard is dropped
+pub fn set_vars(vars: HashMap<String, i128>) -> VarsGuard {
+    VARS.with(|current| *current.borrow_mut() = vars);
+    VarsGuard
+}
+
+/// Clears the vars available to expressions when dropped
+pub struct VarsGuard;
+
+impl Drop for VarsGuard {
+    fn drop(&mut self) {
+        VARS.with(|current| current.borrow_mut().clear());
+    }
+}
+
+/// Evaluates an expression in a config value against the current vars, falling back to the
+/// `CreateFileW` constants
+fn evaluate_with_vars(expression: &str) -> Result<i128, ExprError> {
+    VARS.with(|vars| {
+        let vars = vars.borrow();
+
+        evaluate(expression, &mut |name| {
+            vars.get(var_name(name))
+                .copied()
+                .or_else(|| open_options::constant(name).map(i128::from))
+                .ok_or_else(|| ExprError::UndefinedName(name.to_string()))
+        })
+    })
+}
+
+/// A `[vars]` value before evaluation
+#[derive(Debug, Clone, PartialEq)]
+pub enum RawNumber {
+    Value(i128),
+    Expression(String),
+}
+
+impl<'de> Deserialize<'de> for RawNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawNumberVisitor;
+
+        impl Visitor<'_> for RawNumberVisitor {
+            type Value = RawNumber;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an integer or an integer expression")
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<RawNumber, E> {
+                Ok(RawNumber::Value(value.into()))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<RawNumber, E> {
+                Ok(RawNumber::Value(value.into()))
+            }
+
+            fn visit_str<E: de::Error>(self, expression: &str) -> Result<RawNumber, E> {
+                Ok(RawNumber::Expression(expression.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(RawNumberVisitor)
+    }
+}
+
+/// An integer config value, given either as a literal or as an expression which is
+/// evaluated against the current vars as it is deserialized
+pub struct Number<T>(pub T);
+
+impl<'de, T: TryFrom<i128>> Deserialize<'de> for Number<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = match RawNumber::deserialize(deserializer)? {
+            RawNumber::Value(value) => value,
+            RawNumber::Expression(expression) => evaluate_with_vars(&expression)
+                .map_err(|e| de::Error::custom(format!("in expression `{expression}`: {e}")))?,
+        };
+
+        T::try_from(value)
+            .map(Number)
+            .map_err(|_| de::Error::custom(format!("{value} is out of range for this field")))
+    }
+}
+
+/// For `deserialize_with` on integer fields that accept expressions
+pub fn deserialize_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i128>,
+{
+    Ok(Number::deserialize(deserializer)?.0)
+}
+
+/// For `deserialize_with` on optional integer fields that accept expressions
+pub fn deserialize_option_number<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i128>,
+{
+    Ok(Option::<Number<T>>::deserialize(deserializer)?.map(|number| number.0))
+}
+
+/// For `deserialize_with` on tables of integers that accept expressions
+pub fn deserialize_number_map<'de, D, T>(deserializer: D) -> Result<HashMap<String, T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i128>,
+{
+    Ok(HashMap::<String, Number<T>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, number)| (name, number.0))
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i128),
+    Name(String),
+    Operator(&'static str),
+    OpenParen,
+    CloseParen,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ExprError> {
+    const OPERATORS: [&str; 11] = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~"];
+
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_literal(&rest[..end])?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if c == '\'' {
+            // Character literal, e.g. 'T' for the type of a Linux ioctl
+            let mut chars = rest[1..].chars();
+            let (Some(value), Some('\'')) = (chars.next(), chars.next()) else {
+                return Err(ExprError::Syntax("invalid character literal"));
+            };
+            tokens.push(Token::Number(value as i128));
+            rest = &rest[2 + value.len_utf8()..];
+        } else if c == '(' {
+            tokens.push(Token::OpenParen);
+            rest = &rest[1..];
+        } else if c == ')' {
+            tokens.push(Token::CloseParen);
+            rest = &rest[1..];
+        } else {
+            let operator = OPERATORS
+                .into_iter()
+                .find(|operator| rest.starts_with(operator))
+                .ok_or(ExprError::Syntax("unexpected character in expression"))?;
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn parse_literal(literal: &str) -> Result<i128, ExprError> {
+    let digits = literal.replace('_', "");
+
+    let (digits, radix) = match digits.get(..2) {
+        Some("0x" | "0X") => (&digits[2..], 16),
+        Some("0o" | "0O") => (&digits[2..], 8),
+        Some("0b" | "0B") => (&digits[2..], 2),
+        _ => (&digits[..], 10),
+    };
+
+    i128::from_str_radix(digits, radix).map_err(|e| match e.kind() {
+        std::num::IntErrorKind::PosOverflow => ExprError::Overflow,
+        _ => ExprError::Syntax("invalid integer literal"),
+    })
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: &'a mut dyn FnMut(&str) -> Result<i128, ExprError>,
+}
+
+impl Parser<'_> {
+    /// Binary operators from loosest to tightest binding
+    const PRECEDENCE: [&'static [&'static str]; 5] =
+        [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"]];
+    const MULTIPLICATIVE: &'static [&'static str] = &["*", "/", "%"];
+
+    /// Parses a chain of binary operators at `level` of the precedence table, or at the
+    /// multiplicative level once past the end of it
+    fn binary(&mut self, level: usize) -> Result<i128, ExprError> {
+        let operators = Self::PRECEDENCE
+            .get(level)
+            .copied()
+            .unwrap_or(Self::MULTIPLICATIVE);
+        let operand = |parser: &mut Self| match level < Self::PRECEDENCE.len() {
+            true => parser.binary(level + 1),
+            false => parser.unary(),
+        };
+
+        let mut value = operand(self)?;
+
+        while let Some(Token::Operator(operator)) = self.tokens.get(self.position)
+            && operators.contains(operator)
+        {
+            let operator = *operator;
+            self.position += 1;
+
+            let rhs = operand(self)?;
+            value = apply(operator, value, rhs)?;
+        }
+
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i128, ExprError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or(ExprError::Syntax("unexpected end of expression"))?;
+        self.position += 1;
+
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Name(name) => (self.lookup)(&name),
+            Token::Operator("-") => self.unary()?.checked_neg().ok_or(ExprError::Overflow),
+            Token::Operator("~") => Ok(!self.unary()?),
+            Token::OpenParen => {
+                let value = self.binary(0)?;
+
+                match self.tokens.get(self.position) {
+                    Some(Token::CloseParen) => {
+                        self.position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(ExprError::Syntax("missing closing parenthesis")),
+                }
+            }
+            _ => Err(ExprError::Syntax("expected a number, name or parenthesis")),
+        }
+    }
+}
+
+fn apply(operator: &str, lhs: i128, rhs: i128) -> Result<i128, ExprError> {
+    match operator {
+        "+" => lhs.checked_add(rhs).ok_or(ExprError::Overflow),
+        "-" => lhs.checked_sub(rhs).ok_or(ExprError::Overflow),
+        "*" => lhs.checked_mul(rhs).ok_or(ExprError::Overflow),
+        "/" | "%" if rhs == 0 => Err(ExprError::DivideByZero),
+        "/" => lhs.checked_div(rhs).ok_or(ExprError::Overflow),
+        "%" => lhs.checked_rem(rhs).ok_or(ExprError::Overflow),
+        "<<" => {
+            let shift = u32::try_from(rhs).map_err(|_| ExprError::Overflow)?;
+            let value = lhs.checked_shl(shift).ok_or(ExprError::Overflow)?;
+
+            // checked_shl only checks the shift amount, so check no bits were lost
+            match value >> shift == lhs {
+                true => Ok(value),
+                false => Err(ExprError::Overflow),
+            }
+        }
+        ">>" => {
+            let shift = u32::try_from(rhs).map_err(|_| ExprError::Overflow)?;
+            lhs.checked_shr(shift).ok_or(ExprError::Overflow)
+        }
+        "&" => Ok(lhs & rhs),
+        "^" => Ok(lhs ^ rhs),
+        "|" => Ok(lhs | rhs),
+        _ => unreachable!("unknown binary operator"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate_with(expression: &str, names: &[(&str, i128)]) -> Result<i128, ExprError> {
+        evaluate(expression, &mut |name| {
+            names
+                .iter()
+                .find(|(candidate, _)| *candidate == name)
+                .map(|(_, value)| *value)
+                .ok_or_else(|| ExprError::UndefinedName(name.to_string()))
+        })
+    }
+
+    #[test]
+    fn evaluate_operators_and_precedence() {
+        assert_eq!(Ok(0x1000), evaluate_with("1 << 12", &[]));
+        assert_eq!(
+            Ok(0x18),
+            evaluate_with("HDR_SIZE + 0x10", &[("HDR_SIZE", 8)])
+        );
+        assert_eq!(Ok(7), evaluate_with("1 + 2 * 3", &[]));
+        assert_eq!(Ok(9), evaluate_with("(1 + 2) * 3", &[]));
+        assert_eq!(Ok(0x11), evaluate_with("1 << 4 | 1", &[]));
+        assert_eq!(Ok(-4), evaluate_with("-(0b10 + 0o2)", &[]));
+        assert_eq!(Ok(0xFFFF_FFFF), evaluate_with("~0 & 0xFFFF_FFFF", &[]));
+        assert_eq!(Ok(2), evaluate_with("17 % 5", &[]));
+        assert_eq!(Ok(0x5401), evaluate_with("'T' << 8 | 1", &[]));
+    }
+
+    #[test]
+    fn evaluate_errors() {
+        assert_eq!(
+            Err(ExprError::UndefinedName("MISSING".to_string())),
+            evaluate_with("MISSING + 1", &[])
+        );
+        assert_eq!(Err(ExprError::Overflow), evaluate_with("1 << 127", &[]));
+        assert_eq!(
+            Err(ExprError::Overflow),
+            evaluate_with("0x7FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF + 1", &[])
+        );
+        assert_eq!(Err(ExprError::DivideByZero), evaluate_with("1 / 0", &[]));
+        assert!(matches!(
+            evaluate_with("(1 + 2", &[]),
+            Err(ExprError::Syntax(_))
+        ));
+        assert!(matches!(
+            evaluate_with("1 +", &[]),
+            Err(ExprError::Syntax(_))
+        ));
+        assert!(matches!(
+            evaluate_with("1 $ 2", &[]),
+            Err(ExprError::Syntax(_))
+        ));
+        assert!(matches!(
+            evaluate_with("'TT'", &[]),
+            Err(ExprError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn evaluate_vars_in_any_order() {
+        let raw_vars = HashMap::from([
+            (
+                "count".to_string(),
+                RawNumber::Expression("base * 2".to_string()),
+            ),
+            ("base".to_string(), RawNumber::Value(4)),
+            (
+                "size".to_string(),
+                RawNumber::Expression("vars.count * 8".to_string()),
+            ),
+        ]);
+
+        let vars = evaluate_vars(&raw_vars).unwrap();
+        assert_eq!(8, vars["count"]);
+        assert_eq!(64, vars["size"]);
+
+        let raw_vars = HashMap::from([
+            ("a".to_string(), RawNumber::Expression("b + 1".to_string())),
+            ("b".to_string(), RawNumber::Expression("a + 1".to_string())),
+        ]);
+        assert!(evaluate_vars(&raw_vars).is_err());
+    }
+}
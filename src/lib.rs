@@ -1,53 +1,293 @@
-use crate::dispatch::Dispatcher;
-use serde::Deserialize;
+use crate::allocator::{GuardPage, HostileMemory, HostilePointer, IoBuffer};
+use crate::ctl_code::{CtlCode, IocCode};
+use crate::dispatch::{DispatchOutcome, Dispatcher};
+use crate::error::{BufferError, BufferErrorKind, DispatchError};
+use crate::expr::{Number, RawNumber};
+use crate::open_options::OpenOptions;
+use crate::report::Reporter;
+use base64::Engine;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
 
+pub mod allocator;
+pub mod ctl_code;
 pub mod dispatch;
+pub mod error;
+pub mod expr;
+#[cfg(target_os = "linux")]
+pub mod linux_helpers;
+pub mod mock;
+pub mod open_options;
+pub mod report;
+pub mod transport;
+pub mod validate;
+#[cfg(windows)]
 pub mod win_helpers;
 
-/// Holds commandline arguments.
-/// This currently is only being used for the config file path
+/// Holds commandline arguments: the config file path, optionally preceded by `validate`
+/// and `--format <toml|json|yaml>`
 pub struct Cli {
     pub file_path: std::path::PathBuf,
+    /// Only check the config for problems, rather than prompting for what to send
+    pub validate: bool,
+    /// Format of the config file, if it shouldn't be worked out from its extension
+    pub format: Option<ConfigFormat>,
 }
 
 impl Cli {
     pub fn build(args: &[String]) -> Result<Cli, &'static str> {
-        if args.len() != 2 {
-            return Err("incorrect number of arguments provided");
+        let mut validate = false;
+        let mut format = None;
+        let mut file_path = None;
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--format" => {
+                    let name = args.next().ok_or("--format needs a value")?;
+                    format = Some(name.parse()?);
+                }
+                "validate" if !validate && file_path.is_none() => validate = true,
+                _ if file_path.is_none() => file_path = Some(std::path::PathBuf::from(arg)),
+                _ => return Err("incorrect number of arguments provided"),
+            }
         }
 
-        let file_path = std::path::PathBuf::from(&args[1]);
+        let file_path = file_path.ok_or("incorrect number of arguments provided")?;
+
+        Ok(Cli {
+            file_path,
+            validate,
+            format,
+        })
+    }
+}
+
+/// Formats that a config file can be written in. Every format deserializes into the same
+/// types, so anything that can be written in TOML can be written in the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Works out the format from a file's extension, if it is a known one
+    pub fn from_path(path: &Path) -> Option<ConfigFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        extension.parse().ok()
+    }
+
+    fn parse<T: serde::de::DeserializeOwned>(self, contents: &str) -> Result<T, Box<dyn Error>> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(contents)?,
+            ConfigFormat::Json => serde_json::from_str(contents)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+        })
+    }
+}
+
+impl std::str::FromStr for ConfigFormat {
+    type Err = &'static str;
 
-        Ok(Cli { file_path })
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            _ => Err("config format must be toml, json or yaml"),
+        }
     }
 }
 
 /// Configuration struct that the config TOML is serialised into
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct Config {
-    pub device_name: String, // TODO: Move this onto Ioctl, so it's per-call?
+    /// Path of the device that IOCTLs are sent to, unless they pick their own. Can be left
+    /// out if every IOCTL does.
+    #[serde(default)]
+    pub device_name: String,
+    /// Named devices that IOCTLs can pick with `device`
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    /// Can be left out of files that are only included by others
+    #[serde(default)]
     pub ioctls: Vec<Ioctl>,
+    /// Struct templates that can be instantiated in any IOCTL's input buffer
+    #[serde(default)]
+    pub structs: HashMap<String, StructTemplate>,
+    /// Named values that `Flags` entries can refer to
+    #[serde(default, deserialize_with = "expr::deserialize_number_map")]
+    pub constants: HashMap<String, u64>,
+    /// Groups of named values, referred to by `Flags` entries as `ENUM.MEMBER`
+    #[serde(default, deserialize_with = "deserialize_enums")]
+    pub enums: HashMap<String, HashMap<String, u64>>,
+    /// Values from the `[vars]` table, which any integer in the config can refer to in an
+    /// expression. These are evaluated before the rest of the config is deserialized.
+    #[serde(skip)]
+    pub vars: HashMap<String, i128>,
 }
 
-/// Represents a single IOCTL
+/// The parts of a config file that have to be read before the rest of it can be deserialized
+#[derive(Deserialize)]
+struct RawConfig {
+    /// Paths or glob patterns of other config files to layer underneath this one, relative
+    /// to this file
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    vars: HashMap<String, RawNumber>,
+}
+
+/// A config file that has been read, but not yet deserialized
+struct ConfigFile {
+    path: PathBuf,
+    canonical_path: PathBuf,
+    format: ConfigFormat,
+    contents: String,
+    vars: HashMap<String, RawNumber>,
+}
+
+/// A device object or symlink that IOCTLs can be sent to, declared once under `[[devices]]`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Device {
+    pub name: String,
+    /// Path passed to `CreateFileW`, e.g. `\\.\Example`
+    pub path: String,
+    /// Other names that IOCTLs can use to pick this device
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// How the device is opened, unless an IOCTL overrides it
+    #[serde(flatten)]
+    pub open_options: OpenOptions,
+}
+
+/// A reusable structure layout, declared once under `[structs.NAME]` and placed into
+/// input buffers with a `Struct` entry
 #[derive(Debug, Deserialize, Clone)]
+pub struct StructTemplate {
+    /// Size of the struct. If not provided, the size is taken as the end of the last field.
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    size: Option<usize>,
+    /// Named fields, with offsets relative to the start of the struct. The entry values
+    /// are the defaults used when an instance doesn't override them.
+    fields: Vec<BufferContentEntry>,
+}
+
+/// Represents a single IOCTL
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct Ioctl {
     name: String,
+    /// The IOCTL code, given either directly or as a table of `CtlCode` or `IocCode` parts
+    #[serde(deserialize_with = "ctl_code::deserialize_code")]
     code: u32,
     #[serde(default)]
     overlapped: bool,
+    /// Name or alias of the `[[devices]]` entry to send the IOCTL to
+    device: Option<String>,
+    /// Path of the device to send the IOCTL to, instead of the config's `device_name`. Once
+    /// the config is built, this holds the path that was resolved for the IOCTL.
+    device_name: Option<String>,
+    /// How the device is opened to send the IOCTL. Once the config is built, this also
+    /// holds any options from the IOCTL's `[[devices]]` entry.
+    #[serde(flatten)]
+    open_options: OpenOptions,
+    #[serde(deserialize_with = "expr::deserialize_number")]
     input_buffer_size: usize,
+    #[serde(deserialize_with = "expr::deserialize_number")]
     output_buffer_size: usize,
+    /// Input buffer length passed to DeviceIoControl, if different to `input_buffer_size`
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    declared_input_buffer_size: Option<usize>,
+    /// Number of input buffer bytes actually allocated, if different to `input_buffer_size`
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    allocated_input_buffer_size: Option<usize>,
+    /// Output buffer length passed to DeviceIoControl, if different to `output_buffer_size`
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    declared_output_buffer_size: Option<usize>,
+    /// Number of output buffer bytes actually allocated, if different to `output_buffer_size`
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    allocated_output_buffer_size: Option<usize>,
+    /// Pass a NULL input buffer, whatever the declared size
+    #[serde(default)]
+    null_input: bool,
+    /// Pass a NULL output buffer, whatever the declared size
+    #[serde(default)]
+    null_output: bool,
+    /// Place both buffers flush against an inaccessible guard page
+    #[serde(default)]
+    guard_page: GuardPage,
     input_buffer_content: Option<Vec<BufferContentEntry>>,
+    /// Secondary buffers that `Pointer` entries can write the address of, keyed by name
+    #[serde(default)]
+    aux_buffers: HashMap<String, AuxBuffer>,
+    /// Treat overlapping input buffer entries as an error rather than a warning
+    #[serde(default)]
+    strict: bool,
+    /// Copy of the config's struct templates, so the buffer can be built from the Ioctl alone
+    #[serde(skip)]
+    structs: HashMap<String, StructTemplate>,
+    /// Copy of the config's constants and enum members, keyed by the name used in `Flags`
+    #[serde(skip)]
+    constants: HashMap<String, u64>,
+    /// Line of the config file that each `input_buffer_content` entry is declared on
+    #[serde(skip)]
+    entry_lines: Vec<usize>,
+    /// Config file that the IOCTL was declared in, if it was loaded from one
+    #[serde(skip)]
+    config_file: Option<PathBuf>,
+    /// Line of the config file that the IOCTL is declared on
+    #[serde(skip)]
+    line: Option<usize>,
+}
+
+/// A named secondary buffer, allocated alongside the input buffer for each dispatch so that
+/// structs in the input can point to it
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuxBuffer {
+    #[serde(deserialize_with = "expr::deserialize_number")]
+    size: usize,
+    content: Option<Vec<BufferContentEntry>>,
+    /// Line of the config file that each `content` entry is declared on
+    #[serde(skip)]
+    entry_lines: Vec<usize>,
+}
+
+/// The buffers allocated for a single dispatch. The auxiliary buffers are held here so that
+/// any pointers to them stay valid until the dispatch has finished with the input buffer.
+#[derive(Debug)]
+pub struct PreparedBuffers {
+    /// The input buffer, or None if NULL should be passed instead
+    pub input: Option<IoBuffer>,
+    /// The output buffer, or None if NULL should be passed instead
+    pub output: Option<IoBuffer>,
+    pub aux: HashMap<String, IoBuffer>,
+    /// Memory behind any `HostilePointer` entries
+    pub hostile: HostileMemory,
+    /// Entries in any of the buffers that write to the same bytes, which are warnings
+    /// unless the IOCTL is strict
+    pub overlaps: Vec<BufferError>,
+}
+
+/// Runtime addresses that pointer entries are resolved against
+struct Addresses<'a> {
+    /// Addresses of the auxiliary buffers, keyed by name
+    aux: HashMap<&'a str, u64>,
+    /// Memory behind hostile pointer values, or None if nothing has been allocated
+    hostile: Option<&'a HostileMemory>,
 }
 
 impl fmt::Display for Ioctl {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:0x{:X}", self.name, self.code)
+        write!(f, "{}:0x{:X} ({})", self.name, self.code, self.code_parts())
     }
 }
 
@@ -55,6 +295,9 @@ impl fmt::Display for Ioctl {
 /// to construct the buffer fully before being dispatched
 #[derive(Debug, Deserialize, Clone)]
 pub struct BufferContentEntry {
+    /// Optional name that computed entries can use to refer to this entry
+    name: Option<String>,
+    #[serde(deserialize_with = "expr::deserialize_number")]
     offset: usize,
     #[serde(flatten)]
     entry_data: EntryData,
@@ -63,284 +306,4205 @@ pub struct BufferContentEntry {
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum EntryData {
-    U8 { value: u8 },
-    U16 { value: u16 },
-    U32 { value: u32 },
-    U64 { value: u64 },
-    String8 { value: String },
-    Fill { value: u8, length: usize },
+    U8 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: u8,
+    },
+    U16 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: u16,
+        #[serde(default)]
+        endian: Endian,
+    },
+    U32 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: u32,
+        #[serde(default)]
+        endian: Endian,
+    },
+    U64 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: u64,
+        #[serde(default)]
+        endian: Endian,
+    },
+    I8 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: i8,
+    },
+    I16 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: i16,
+        #[serde(default)]
+        endian: Endian,
+    },
+    I32 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: i32,
+        #[serde(default)]
+        endian: Endian,
+    },
+    I64 {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: i64,
+        #[serde(default)]
+        endian: Endian,
+    },
+    F32 {
+        value: f32,
+        #[serde(default)]
+        endian: Endian,
+    },
+    F64 {
+        value: f64,
+        #[serde(default)]
+        endian: Endian,
+    },
+    String8 {
+        value: String,
+    },
+    /// UTF-16LE string, as used for `WCHAR` paths and names
+    String16 {
+        value: String,
+        #[serde(default)]
+        null_terminate: bool,
+        /// Pads the encoded string with zero bytes up to this many bytes
+        #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+        pad_to: Option<usize>,
+        /// Truncates the string to this many UTF-16 code units, before any terminator
+        #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+        max_length: Option<usize>,
+    },
+    Fill {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: u8,
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        length: usize,
+    },
+    /// Raw bytes given as a hex string, e.g. `"4d5a9000"`
+    Bytes {
+        #[serde(deserialize_with = "deserialize_hex")]
+        value: Vec<u8>,
+    },
+    /// Raw bytes given as a standard base64 string
+    Base64 {
+        #[serde(deserialize_with = "deserialize_base64")]
+        value: Vec<u8>,
+    },
+    /// Raw bytes read from a file at send time. Relative paths are resolved against the
+    /// directory of the config file.
+    File {
+        path: PathBuf,
+        #[serde(default, deserialize_with = "expr::deserialize_number")]
+        file_offset: u64,
+        /// Number of bytes to read. Reads to the end of the file if not provided.
+        #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+        length: Option<usize>,
+    },
+    /// Size in bytes of the named `target` entry, resolved once the buffer is laid out
+    SizeOf {
+        target: String,
+        #[serde(default)]
+        width: IntWidth,
+        #[serde(default)]
+        endian: Endian,
+    },
+    /// Offset of the named `target` entry from the start of the buffer
+    OffsetOf {
+        target: String,
+        #[serde(default)]
+        width: IntWidth,
+        #[serde(default)]
+        endian: Endian,
+    },
+    /// Size of the whole buffer that the entry is in
+    BufferSize {
+        #[serde(default)]
+        width: IntWidth,
+        #[serde(default)]
+        endian: Endian,
+    },
+    /// Runtime address of the named auxiliary buffer, plus `add` bytes
+    Pointer {
+        target: String,
+        #[serde(default, deserialize_with = "expr::deserialize_number")]
+        add: i64,
+        #[serde(default)]
+        width: PointerWidth,
+    },
+    /// One of the classic hostile pointer values, e.g. `"kernel"` or `"read_only"`, plus
+    /// `add` bytes
+    HostilePointer {
+        value: HostilePointer,
+        #[serde(default, deserialize_with = "expr::deserialize_number")]
+        add: i64,
+        #[serde(default)]
+        width: PointerWidth,
+    },
+    /// An instance of a struct template, with any fields to override by name
+    Struct {
+        #[serde(rename = "struct")]
+        struct_name: String,
+        #[serde(default)]
+        overrides: HashMap<String, EntryData>,
+    },
+    /// Lays out `count` records, each made up of `entries` with offsets relative to the
+    /// start of the record, every `stride` bytes
+    Repeat {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        count: usize,
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        stride: usize,
+        entries: Vec<BufferContentEntry>,
+    },
+    /// Lays out a list of values back to back, or every `stride` bytes if provided
+    Array {
+        values: Vec<EntryData>,
+        #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+        stride: Option<usize>,
+    },
+    /// Symbolic names from `[constants]` or `[enums]` that are ORed together, and then ORed
+    /// into the existing bytes of the buffer
+    Flags {
+        value: Vec<String>,
+        #[serde(default)]
+        width: IntWidth,
+        #[serde(default)]
+        endian: Endian,
+    },
+    /// A value of `bit_width` bits placed `bit_offset` bits from the start of the entry, which
+    /// is ORed into the existing bytes of the buffer
+    Bits {
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        bit_offset: usize,
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        bit_width: usize,
+        #[serde(deserialize_with = "expr::deserialize_number")]
+        value: u64,
+    },
 }
 
-impl Config {
-    /// Reads an input config TOML file, serialises it, and returns a Config struct
-    pub fn build(cli: &Cli) -> Result<Config, Box<dyn Error>> {
-        let toml_contents = fs::read_to_string(&cli.file_path)?;
+/// Limit on how deeply struct, repeat and array entries can be nested inside each other,
+/// which also catches struct templates that contain themselves.
+const MAX_NESTING_DEPTH: usize = 16;
 
-        let config: Config = toml::from_str(&toml_contents)?;
+/// Width, in bytes, of an integer field whose value is worked out by ioctiller rather
+/// than given in the config. Defaults to 4 as most length/offset fields are `ULONG`s.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "usize")]
+pub struct IntWidth(usize);
 
-        Ok(config)
+impl Default for IntWidth {
+    fn default() -> Self {
+        IntWidth(4)
     }
+}
 
-    /// Prints the ioctls on the Config struct
-    pub fn print_inputs(&self) {
-        for (i, ioctl) in self.ioctls.iter().enumerate() {
-            println!("[{i}]: {}(0x{:X})", ioctl.name, ioctl.code);
+impl TryFrom<usize> for IntWidth {
+    type Error = &'static str;
+
+    fn try_from(width: usize) -> Result<Self, Self::Error> {
+        match width {
+            1 | 2 | 4 | 8 => Ok(IntWidth(width)),
+            _ => Err("integer width must be 1, 2, 4 or 8"),
         }
     }
 }
 
-impl Ioctl {
-    /// Iterates over any input buffer content entries on the Config struct and
-    /// uses them to construct an input buffer of type Vec<u8> that can be used
-    /// in dispatch calls.
-    pub fn build_input_buffer(&self) -> Result<Vec<u8>, &'static str> {
-        let mut buffer = vec![0; self.input_buffer_size as usize];
+/// Width, in bytes, of a pointer entry. Defaults to the pointer width of the host, but can
+/// be set to 4 for drivers that take 32-bit structures.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "usize")]
+pub struct PointerWidth(usize);
 
-        let buffer_content_entries = match &self.input_buffer_content {
-            Some(buffer_content_entries) => buffer_content_entries,
-            None => return Ok(buffer),
-        };
+impl Default for PointerWidth {
+    fn default() -> Self {
+        PointerWidth(size_of::<usize>())
+    }
+}
 
-        for entry in buffer_content_entries {
-            match &entry.entry_data {
-                crate::EntryData::U8 { value } => {
-                    check_buffer_overwrite(entry.offset, size_of::<u8>(), self.input_buffer_size)?;
+impl TryFrom<usize> for PointerWidth {
+    type Error = &'static str;
 
-                    buffer[entry.offset as usize] = *value;
-                }
-                crate::EntryData::U16 { value } => {
-                    let u16_size = size_of::<u16>();
-                    check_buffer_overwrite(entry.offset, u16_size, self.input_buffer_size)?;
+    fn try_from(width: usize) -> Result<Self, Self::Error> {
+        match width {
+            4 | 8 => Ok(PointerWidth(width)),
+            _ => Err("pointer width must be 4 or 8"),
+        }
+    }
+}
 
-                    buffer[entry.offset..entry.offset + u16_size]
-                        .copy_from_slice(&(*value).to_le_bytes());
-                }
-                crate::EntryData::U32 { value } => {
-                    let u32_size = size_of::<u32>();
-                    check_buffer_overwrite(entry.offset, u32_size, self.input_buffer_size)?;
+impl IntWidth {
+    /// Encodes a value into this many bytes, erroring if it would be truncated
+    fn encode(self, value: u64, endian: Endian) -> Result<Vec<u8>, &'static str> {
+        if self.0 < size_of::<u64>() && value >> (self.0 * 8) != 0 {
+            return Err("Computed value does not fit in entry width");
+        }
 
-                    buffer[entry.offset..entry.offset + u32_size]
-                        .copy_from_slice(&(*value).to_le_bytes());
-                }
-                crate::EntryData::U64 { value } => {
-                    let u64_size = size_of::<u64>();
-                    check_buffer_overwrite(entry.offset, u64_size, self.input_buffer_size)?;
+        Ok(endian.order(value.to_le_bytes()[..self.0].to_vec()))
+    }
+}
 
-                    buffer[entry.offset..entry.offset + u64_size]
-                        .copy_from_slice(&(*value).to_le_bytes());
-                }
-                crate::EntryData::String8 { value } => {
-                    let str_size = value.len();
-                    check_buffer_overwrite(entry.offset, str_size, self.input_buffer_size)?;
+/// Where an entry ended up once the buffer has been laid out
+#[derive(Debug, Clone, Copy)]
+struct EntryLayout {
+    offset: usize,
+    size: usize,
+}
 
-                    buffer[entry.offset..entry.offset + str_size].copy_from_slice(value.as_bytes());
-                }
-                crate::EntryData::Fill { value, length } => {
-                    check_buffer_overwrite(entry.offset, *length, self.input_buffer_size)?;
+/// A single entry to be written once any struct instances have been expanded, with its
+/// offset relative to the start of the buffer
+struct ExpandedEntry<'a> {
+    /// Index of the `input_buffer_content` entry that this was expanded from
+    index: usize,
+    /// Part of that entry that this is, e.g. `[1].Length`, or empty if it wasn't expanded
+    part: String,
+    /// Name qualified with the names of the struct instances it sits in, e.g. `hdr.Length`
+    name: Option<String>,
+    /// Prefix of the struct instance this entry sits in, used to resolve computed targets
+    /// against sibling fields first
+    scope: String,
+    offset: usize,
+    entry_data: &'a EntryData,
+}
 
-                    buffer[entry.offset..entry.offset + length].fill(*value);
-                }
-            }
+/// Entries and named instances produced by expanding an input buffer's content
+#[derive(Default)]
+struct Expansion<'a> {
+    entries: Vec<ExpandedEntry<'a>>,
+    instances: Vec<ExpandedInstance>,
+    /// Index of the `input_buffer_content` entry currently being expanded
+    index: usize,
+    /// Part of that entry currently being expanded, e.g. `[1].Length`
+    part: String,
+    /// Size of the buffer being expanded into, which containers are checked against
+    buffer_size: usize,
+}
+
+impl Expansion<'_> {
+    /// Appends a struct field name or `[index]` to the part being expanded, and returns the
+    /// length to truncate it back to once the part has been expanded
+    fn enter_part(&mut self, component: &str) -> usize {
+        let len = self.part.len();
+
+        if !self.part.is_empty() && !component.starts_with('[') {
+            self.part.push('.');
         }
+        self.part.push_str(component);
 
-        Ok(buffer)
+        len
     }
 }
 
-/// Helper function to check that a buffer content entry does not exceed the buffer
-/// size. This would error anyway due to Rust's bounds checking, but we handle anyway to
-/// inform the user.
-fn check_buffer_overwrite(
+/// The entries and size of one of an IOCTL's buffers: the input buffer or an auxiliary
+/// buffer
+struct BufferSpec<'a> {
+    /// Name of the auxiliary buffer, or None for the input buffer
+    aux_name: Option<&'a str>,
+    size: usize,
+    content: &'a [BufferContentEntry],
+    entry_lines: &'a [usize],
+}
+
+impl fmt::Display for BufferSpec<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.aux_name {
+            Some(aux_name) => write!(f, "aux buffer {aux_name}"),
+            None => write!(f, "input buffer"),
+        }
+    }
+}
+
+/// The bytes of a single entry and where they are written, once the buffer is laid out
+struct LaidOutEntry {
+    /// Index of the `input_buffer_content` entry that this was expanded from
+    index: usize,
+    /// Part of that entry that this is, or empty if it wasn't expanded
+    part: String,
     offset: usize,
-    entry_size: usize,
-    buffer_size: usize,
-) -> Result<(), &'static str> {
-    if (offset + entry_size) > buffer_size {
-        return Err("Input buffer entry content is out of bounds");
+    bytes: Vec<u8>,
+    /// Whether the bytes are ORed into the buffer rather than overwriting it
+    bitwise: bool,
+}
+
+/// Two input buffer entries, or two parts of the same struct, repeat or array entry, that
+/// write to the same bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlap {
+    /// Index of the first `input_buffer_content` entry
+    pub first: usize,
+    /// Part of the first entry that overlaps, e.g. `[1].Length`, or empty if the entry isn't
+    /// a struct, repeat or array
+    pub first_part: String,
+    /// Index of the second `input_buffer_content` entry
+    pub second: usize,
+    /// Part of the second entry that overlaps
+    pub second_part: String,
+    /// Start of the overlapping byte range
+    pub start: usize,
+    /// End (exclusive) of the overlapping byte range
+    pub end: usize,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let describe = |index: usize, part: &str| match part {
+            "" => index.to_string(),
+            part => format!("{index} ({part})"),
+        };
+
+        write!(
+            f,
+            "entries {} and {} overlap at 0x{:X}..0x{:X}",
+            describe(self.first, &self.first_part),
+            describe(self.second, &self.second_part),
+            self.start,
+            self.end
+        )
     }
-    Ok(())
 }
 
-/// Wrapper around the dispatcher that simply calls the dispatch method.
-pub fn send_single(dispatcher: &impl Dispatcher) -> windows::core::Result<()> {
-    dispatcher.dispatch()
+/// A named struct, repeat or array instance, which computed entries can also target
+struct ExpandedInstance {
+    /// Index of the `input_buffer_content` entry that this was expanded from
+    index: usize,
+    name: String,
+    offset: usize,
+    size: Option<usize>,
+    /// Range of the expanded entries that make up this instance
+    entries: std::ops::Range<usize>,
+    /// Range of the named instances nested inside this instance
+    instances: std::ops::Range<usize>,
 }
 
-/// Launches num_threads number of threads and runs the same dispatcher in each one.
-pub fn fuzz_single<D>(dispatcher: D, num_threads: u32) -> windows::core::Result<()>
+/// Deserializes a hex string into raw bytes. Whitespace between digits is ignored so
+/// that long blobs can be split up for readability.
+pub(crate) fn deserialize_hex<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
 where
-    D: Dispatcher + Send + Sync + Clone + 'static, // TODO: This presumably is not the right way
-                                                   // to do this
+    D: Deserializer<'de>,
 {
-    let mut handles = vec![];
+    let hex_string = String::deserialize(deserializer)?;
+    let digits: Vec<u8> = hex_string
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
 
-    for _ in 0..num_threads {
-        let dispatcher_copy = dispatcher.clone();
-        let handle = thread::spawn(move || {
-            dispatcher_copy
-                .dispatch()
-                .expect("Error running dispatch in thread");
-        });
+    if !digits.len().is_multiple_of(2) {
+        return Err(serde::de::Error::custom(
+            "hex string must contain an even number of digits",
+        ));
+    }
 
-        handles.push(handle);
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| serde::de::Error::custom("invalid hex digit in hex string"))
+        })
+        .collect()
+}
+
+/// Reads the config file at `path`, and before it every file it includes, so that each file
+/// comes after the files layered underneath it. `including` holds the files currently being
+/// read, to catch include cycles. A file that is included more than once is only read the
+/// first time.
+///
+/// The format of each file is `format` if given, or else worked out from its extension,
+/// falling back to TOML. Included files are always read by extension, as the format given
+/// on the command line is only for the config file named there.
+fn read_config_files(
+    path: &Path,
+    format: Option<ConfigFormat>,
+    config_files: &mut Vec<ConfigFile>,
+    including: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let canonical_path = fs::canonicalize(path).map_err(|e| format!("{}: {e}", path.display()))?;
+
+    if including.contains(&canonical_path) {
+        return Err(format!("{} includes itself", path.display()).into());
     }
 
-    for handle in handles {
-        handle.join().unwrap();
+    if config_files
+        .iter()
+        .any(|config_file| config_file.canonical_path == canonical_path)
+    {
+        return Ok(());
     }
 
-    Ok(())
-}
+    let format = format
+        .or_else(|| ConfigFormat::from_path(path))
+        .unwrap_or(ConfigFormat::Toml);
 
-pub fn fuzz_multiple<D>(dispatchers: Vec<D>) -> windows::core::Result<()>
-where
-    D: Dispatcher + Send + Sync + Clone + 'static,
-{
-    let mut handles = vec![];
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let raw_config: RawConfig = format
+        .parse(&contents)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
 
-    for dispatcher in dispatchers {
-        let dispatcher_copy = dispatcher.clone();
+    including.push(canonical_path.clone());
 
-        let handle = thread::spawn(move || {
-            dispatcher_copy
-                .dispatch()
-                .expect("Error running dispatch in thread");
-        });
+    let config_dir = path.parent().unwrap_or(Path::new(""));
+    for pattern in &raw_config.include {
+        let pattern_path = config_dir.join(pattern);
+        let mut included_paths = glob::glob(&pattern_path.to_string_lossy())
+            .map_err(|e| format!("{}: invalid include {pattern}: {e}", path.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}: include {pattern}: {e}", path.display()))?;
 
-        handles.push(handle);
-    }
+        if included_paths.is_empty() {
+            return Err(format!("{}: include {pattern} matched no files", path.display()).into());
+        }
 
-    for handle in handles {
-        handle.join().unwrap();
+        // glob already yields paths in alphabetical order, but make sure of it
+        included_paths.sort();
+
+        for included_path in included_paths {
+            read_config_files(&included_path, None, config_files, including)?;
+        }
     }
 
+    including.pop();
+
+    config_files.push(ConfigFile {
+        path: path.to_path_buf(),
+        canonical_path,
+        format,
+        contents,
+        vars: raw_config.vars,
+    });
+
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Deserializes the `[enums]` table, whose member values can be expressions
+fn deserialize_enums<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, HashMap<String, u64>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        HashMap::<String, HashMap<String, Number<u64>>>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(enum_name, members)| {
+                let members = members
+                    .into_iter()
+                    .map(|(member_name, value)| (member_name, value.0))
+                    .collect();
+                (enum_name, members)
+            })
+            .collect(),
+    )
+}
 
-    #[test]
-    fn cli_build_correct_cmdline_args() {
-        let args: Vec<String> = vec!["ioctiller.exe".to_string(), "C:\\test.toml".to_string()];
+/// Deserializes a standard base64 string into raw bytes
+fn deserialize_base64<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let base64_string = String::deserialize(deserializer)?;
 
-        let cli = Cli::build(&args).unwrap();
-        let correct_path = std::path::PathBuf::from("C:\\test.toml");
+    base64::engine::general_purpose::STANDARD
+        .decode(base64_string.trim())
+        .map_err(serde::de::Error::custom)
+}
 
-        assert_eq!(correct_path, cli.file_path);
-    }
+/// Reads the contents of a `File` entry
+fn read_file_entry(
+    path: &Path,
+    offset: u64,
+    length: Option<usize>,
+) -> Result<Vec<u8>, &'static str> {
+    let mut file = fs::File::open(path).map_err(|_| "Failed to open file entry")?;
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|_| "Failed to seek to file entry offset")?;
 
-    #[test]
-    fn cli_build_no_cmdline_args() {
-        let args: Vec<String> = vec!["ioctiller.exe".to_string()];
+    let mut bytes = Vec::new();
 
-        assert!(Cli::build(&args).is_err());
+    match length {
+        Some(length) => {
+            bytes.resize(length, 0);
+            file.read_exact(&mut bytes)
+                .map_err(|_| "File entry is shorter than the requested length")?;
+        }
+        None => {
+            file.read_to_end(&mut bytes)
+                .map_err(|_| "Failed to read file entry")?;
+        }
     }
 
-    #[test]
-    fn cli_build_too_many_cmdline_args() {
-        let args: Vec<String> = vec![
-            "iotctiller.exe".to_string(),
-            "C:\\test.toml".to_string(),
-            "foobar".to_string(),
-        ];
+    Ok(bytes)
+}
 
-        assert!(Cli::build(&args).is_err());
-    }
+/// Byte order used when writing multi-byte numeric entries. Defaults to little-endian,
+/// which is what Windows drivers will expect for almost everything other than
+/// network-order headers.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
 
-    #[test]
-    fn build_buffer_success() {
-        let ioctl = Ioctl {
-            code: 0x10000,
-            overlapped: false,
-            name: "IOCTL_TEST".to_string(),
-            input_buffer_size: 0x70,
-            output_buffer_size: 0x8,
-            input_buffer_content: Some(vec![
-                BufferContentEntry {
-                    offset: 0x0,
-                    entry_data: EntryData::U32 { value: 0x1337C0DE },
-                },
-                BufferContentEntry {
-                    offset: 0x10,
-                    entry_data: EntryData::U64 {
-                        value: 0xDEADBEEFCAFEBABE,
-                    },
-                },
-                BufferContentEntry {
-                    offset: 0x20,
-                    entry_data: EntryData::U8 { value: 0x41 },
-                },
-                BufferContentEntry {
-                    offset: 0x28,
-                    entry_data: EntryData::U16 { value: 0x5A4D },
-                },
-                BufferContentEntry {
-                    offset: 0x30,
-                    entry_data: EntryData::String8 {
-                        value: "foobar".to_string(),
-                    },
-                },
-                BufferContentEntry {
-                    offset: 0x40,
-                    entry_data: EntryData::Fill {
-                        value: 0x24,
-                        length: 0x30,
-                    },
-                },
-            ]),
-        };
+impl Endian {
+    /// Takes the little-endian encoding of a value and returns it in this byte order
+    fn order(self, mut le_bytes: Vec<u8>) -> Vec<u8> {
+        if self == Endian::Big {
+            le_bytes.reverse();
+        }
 
-        let correct_buffer = vec![
-            0xDE, 0xC0, 0x37, 0x13, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
-            0xBE, 0xBA, 0xFE, 0xCA, 0xEF, 0xBE, 0xAD, 0xDE, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
-            0x41, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x4D, 0x5A, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
-            0x66, 0x6f, 0x6f, 0x62, 0x61, 0x72, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
-            0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
-            0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
-            0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
-            0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
-        ];
+        le_bytes
+    }
+}
 
-        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+impl EntryData {
+    /// Returns the width of entries whose value depends on the rest of the buffer, or
+    /// None if the entry can be encoded on its own.
+    fn computed_width(&self) -> Option<usize> {
+        match self {
+            EntryData::SizeOf { width, .. }
+            | EntryData::OffsetOf { width, .. }
+            | EntryData::BufferSize { width, .. } => Some(width.0),
+            EntryData::Pointer { width, .. } | EntryData::HostilePointer { width, .. } => {
+                Some(width.0)
+            }
+            _ => None,
+        }
+    }
 
-        assert_eq!(correct_buffer, input_buffer);
+    /// Returns whether this entry is ORed into the buffer rather than overwriting it
+    fn is_bitwise(&self) -> bool {
+        matches!(self, EntryData::Flags { .. } | EntryData::Bits { .. })
     }
 
-    #[test]
-    fn build_buffer_no_entries() {
-        let ioctl = Ioctl {
-            code: 0x10000,
-            overlapped: false,
-            name: "IOCTL_TEST".to_string(),
-            input_buffer_size: 0x60,
-            output_buffer_size: 0x8,
-            input_buffer_content: None,
-        };
+    /// Returns the raw bytes for a computed entry, using find_target to look up the layout
+    /// of named entries in the buffer, and addresses for any pointers.
+    fn resolve_computed(
+        &self,
+        find_target: impl Fn(&str) -> Option<EntryLayout>,
+        addresses: &Addresses,
+        buffer_size: usize,
+    ) -> Result<Vec<u8>, &'static str> {
+        let find_target =
+            |target: &String| find_target(target).ok_or("Computed entry target not found");
 
-        let correct_buffer = vec![0; 0x60];
+        match self {
+            EntryData::SizeOf {
+                target,
+                width,
+                endian,
+            } => width.encode(find_target(target)?.size as u64, *endian),
+            EntryData::OffsetOf {
+                target,
+                width,
+                endian,
+            } => width.encode(find_target(target)?.offset as u64, *endian),
+            EntryData::BufferSize { width, endian } => width.encode(buffer_size as u64, *endian),
+            EntryData::Pointer { target, add, width } => {
+                let address = addresses
+                    .aux
+                    .get(target.as_str())
+                    .ok_or("Pointer entry refers to an unknown aux buffer")?
+                    .wrapping_add_signed(*add);
 
-        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+                IntWidth(width.0)
+                    .encode(address, Endian::Little)
+                    .map_err(|_| "Pointer does not fit in entry width")
+            }
+            EntryData::HostilePointer { value, add, width } => {
+                let address = value
+                    .address(addresses.hostile, width.0)
+                    .wrapping_add_signed(*add);
 
-        assert_eq!(correct_buffer, input_buffer);
+                IntWidth(width.0)
+                    .encode(address, Endian::Little)
+                    .map_err(|_| "Pointer does not fit in entry width")
+            }
+            _ => self.to_bytes(),
+        }
     }
 
-    #[test]
-    fn build_buffer_oob() {
-        let ioctl = Ioctl {
-            code: 0x10000,
-            overlapped: false,
-            name: "IOCTL_TEST".to_string(),
-            input_buffer_size: 0x60,
-            output_buffer_size: 0x8,
-            input_buffer_content: Some(vec![BufferContentEntry {
-                offset: 0x60,
-                entry_data: EntryData::U32 { value: 0x1337C0DE },
-            }]),
-        };
+    /// Number of bytes written by entries whose size comes straight from the config rather
+    /// than their contents, worked out without encoding them. These can be far larger than
+    /// any buffer, so their bounds are checked before any bytes are produced.
+    fn sized_by_config(&self) -> Result<Option<usize>, &'static str> {
+        match self {
+            EntryData::Fill { length, .. } => Ok(Some(*length)),
+            EntryData::String16 {
+                value,
+                null_terminate,
+                pad_to: Some(pad_to),
+                max_length,
+            } => {
+                let code_units = value
+                    .encode_utf16()
+                    .count()
+                    .min(max_length.unwrap_or(usize::MAX))
+                    + usize::from(*null_terminate);
+
+                Ok(Some((code_units * 2).max(*pad_to)))
+            }
+            EntryData::Bits {
+                bit_offset,
+                bit_width,
+                ..
+            } => Ok(Some(bits_end(*bit_offset, *bit_width)?.div_ceil(8))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Makes the path of a `File` entry, or of any `File` entries nested inside this one,
+    /// relative to `config_dir` if it is relative
+    fn resolve_file_paths(&mut self, config_dir: &Path) {
+        match self {
+            EntryData::File { path, .. } if path.is_relative() => {
+                *path = config_dir.join(&path);
+            }
+            EntryData::Struct { overrides, .. } => {
+                for entry_data in overrides.values_mut() {
+                    entry_data.resolve_file_paths(config_dir);
+                }
+            }
+            EntryData::Repeat { entries, .. } => {
+                for entry in entries {
+                    entry.entry_data.resolve_file_paths(config_dir);
+                }
+            }
+            EntryData::Array { values, .. } => {
+                for entry_data in values {
+                    entry_data.resolve_file_paths(config_dir);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the raw bytes that this entry will write into the buffer
+    fn to_bytes(&self) -> Result<Vec<u8>, &'static str> {
+        let bytes = match self {
+            EntryData::U8 { value } => vec![*value],
+            EntryData::U16 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::U32 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::U64 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::I8 { value } => value.to_le_bytes().to_vec(),
+            EntryData::I16 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::I32 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::I64 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::F32 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::F64 { value, endian } => endian.order(value.to_le_bytes().to_vec()),
+            EntryData::String8 { value } => value.as_bytes().to_vec(),
+            EntryData::String16 {
+                value,
+                null_terminate,
+                pad_to,
+                max_length,
+            } => {
+                let mut code_units: Vec<u16> = value.encode_utf16().collect();
+
+                if let Some(max_length) = max_length {
+                    code_units.truncate(*max_length);
+                }
+
+                if *null_terminate {
+                    code_units.push(0);
+                }
+
+                let mut bytes: Vec<u8> = code_units
+                    .iter()
+                    .flat_map(|code_unit| code_unit.to_le_bytes())
+                    .collect();
+
+                if let Some(pad_to) = pad_to
+                    && bytes.len() < *pad_to
+                {
+                    bytes.resize(*pad_to, 0);
+                }
+
+                bytes
+            }
+            EntryData::Fill { value, length } => vec![*value; *length],
+            EntryData::Bytes { value } => value.clone(),
+            EntryData::Base64 { value } => value.clone(),
+            EntryData::File {
+                path,
+                file_offset,
+                length,
+            } => read_file_entry(path, *file_offset, *length)?,
+            EntryData::SizeOf { .. }
+            | EntryData::OffsetOf { .. }
+            | EntryData::BufferSize { .. }
+            | EntryData::Pointer { .. }
+            | EntryData::HostilePointer { .. } => {
+                return Err("Computed entries can only be resolved as part of a buffer");
+            }
+            EntryData::Bits {
+                bit_offset,
+                bit_width,
+                value,
+            } => {
+                if *bit_width == 0 || *bit_width > u64::BITS as usize {
+                    return Err("Bits entry width must be between 1 and 64");
+                }
+
+                if *bit_width < u64::BITS as usize && value >> bit_width != 0 {
+                    return Err("Bits entry value does not fit in its bit width");
+                }
+
+                let mut bytes = vec![0; bits_end(*bit_offset, *bit_width)?.div_ceil(8)];
+
+                for bit in 0..*bit_width {
+                    if value >> bit & 1 == 1 {
+                        let buffer_bit = bit_offset + bit;
+                        bytes[buffer_bit / 8] |= 1 << (buffer_bit % 8);
+                    }
+                }
+
+                bytes
+            }
+            EntryData::Flags { .. } => {
+                return Err("Flags entries can only be resolved as part of a buffer");
+            }
+            EntryData::Struct { .. } | EntryData::Repeat { .. } | EntryData::Array { .. } => {
+                return Err(
+                    "Struct, Repeat and Array entries can only be expanded as part of a buffer",
+                );
+            }
+        };
+
+        Ok(bytes)
+    }
+}
+
+impl Config {
+    /// Reads an input config file, along with any files it includes, serialises them, and
+    /// returns a Config struct. Each file can be TOML, JSON or YAML.
+    ///
+    /// Included files are layered underneath the file that includes them, in the order
+    /// they are listed, so later files win wherever two define the same device, struct,
+    /// constant, enum or var. Defining the same IOCTL twice is an error.
+    pub fn build(cli: &Cli) -> Result<Config, Box<dyn Error>> {
+        let mut config_files = Vec::new();
+        read_config_files(
+            &cli.file_path,
+            cli.format,
+            &mut config_files,
+            &mut Vec::new(),
+        )?;
+
+        // Vars have to be evaluated first, so expressions in any file can refer to them
+        let mut raw_vars = HashMap::new();
+        for config_file in &mut config_files {
+            raw_vars.extend(config_file.vars.drain());
+        }
+        let vars = expr::evaluate_vars(&raw_vars)?;
+
+        let mut config = Config::default();
+        {
+            let _vars = expr::set_vars(vars.clone());
+
+            for config_file in &config_files {
+                let mut layer: Config = config_file
+                    .format
+                    .parse(&config_file.contents)
+                    .map_err(|e| format!("{}: {e}", config_file.path.display()))?;
+
+                // Line numbers are only known for TOML, as it is the only format parsed
+                // with spans
+                if config_file.format == ConfigFormat::Toml {
+                    layer.record_entry_lines(&config_file.contents);
+                }
+
+                if let Some(config_dir) = config_file.path.parent() {
+                    layer.resolve_file_paths(config_dir);
+                }
+
+                for ioctl in &mut layer.ioctls {
+                    ioctl.config_file = Some(config_file.path.clone());
+                }
+
+                config.merge(layer)?;
+            }
+        }
+        config.vars = vars;
+
+        config.resolve_devices()?;
+        config.share_definitions();
+
+        Ok(config)
+    }
+
+    /// Layers another config file's definitions on top of this one
+    fn merge(&mut self, layer: Config) -> Result<(), Box<dyn Error>> {
+        if !layer.device_name.is_empty() {
+            self.device_name = layer.device_name;
+        }
+
+        for device in layer.devices {
+            match self
+                .devices
+                .iter_mut()
+                .find(|existing| existing.name == device.name)
+            {
+                Some(existing) => *existing = device,
+                None => self.devices.push(device),
+            }
+        }
+
+        for ioctl in layer.ioctls {
+            if let Some(existing) = self
+                .ioctls
+                .iter()
+                .find(|existing| existing.name == ioctl.name)
+            {
+                if existing.config_file != ioctl.config_file {
+                    return Err(format!(
+                        "{} is defined in both {} and {}",
+                        ioctl.name,
+                        existing.declared_at(),
+                        ioctl.declared_at()
+                    )
+                    .into());
+                }
+
+                let lines = match (existing.line, ioctl.line) {
+                    (Some(first), Some(second)) => format!(" (lines {first} and {second})"),
+                    _ => String::new(),
+                };
+
+                return Err(format!(
+                    "{} is defined twice in {}{lines}",
+                    ioctl.name,
+                    ioctl.config_file_name()
+                )
+                .into());
+            }
+
+            self.ioctls.push(ioctl);
+        }
+
+        self.structs.extend(layer.structs);
+        self.constants.extend(layer.constants);
+        self.enums.extend(layer.enums);
+
+        Ok(())
+    }
+
+    /// Works out the device path for each Ioctl, from its own `device_name`, the
+    /// `[[devices]]` entry it picks, or the config's `device_name`, in that order. Open
+    /// options that the Ioctl doesn't set are taken from its `[[devices]]` entry.
+    fn resolve_devices(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut devices: HashMap<&str, &Device> = HashMap::new();
+
+        for device in &self.devices {
+            for name in std::iter::once(&device.name).chain(&device.aliases) {
+                if devices.insert(name, device).is_some() {
+                    return Err(format!("device name {name} is used more than once").into());
+                }
+            }
+        }
+
+        for ioctl in &mut self.ioctls {
+            let path = match (&ioctl.device_name, &ioctl.device) {
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "{} in {} sets both device and device_name, only one can be used",
+                        ioctl.name,
+                        ioctl.config_file_name()
+                    )
+                    .into());
+                }
+                (Some(device_name), None) => device_name.clone(),
+                (None, Some(device)) => {
+                    let device = devices.get(device.as_str()).ok_or_else(|| {
+                        format!(
+                            "{} in {} refers to unknown device {device}",
+                            ioctl.name,
+                            ioctl.config_file_name()
+                        )
+                    })?;
+
+                    ioctl.open_options = ioctl.open_options.or(&device.open_options);
+                    device.path.clone()
+                }
+                (None, None) if !self.device_name.is_empty() => self.device_name.clone(),
+                (None, None) => {
+                    return Err(format!(
+                        "{} has no device, and the config has no default device_name",
+                        ioctl.name
+                    )
+                    .into());
+                }
+            };
+
+            ioctl.device_name = Some(path);
+        }
+
+        Ok(())
+    }
+
+    /// Paths of every device that the IOCTLs are sent to, in the order they first appear
+    pub fn device_names(&self) -> Vec<&str> {
+        let mut device_names = Vec::new();
+
+        for ioctl in &self.ioctls {
+            let device_name = ioctl.device_name();
+            if !device_names.contains(&device_name) {
+                device_names.push(device_name);
+            }
+        }
+
+        device_names
+    }
+
+    /// Gives each Ioctl a copy of the struct templates and constants, so that it can build
+    /// its input buffer on its own. Enum members are added to the constants as `ENUM.MEMBER`.
+    fn share_definitions(&mut self) {
+        let mut constants = self.constants.clone();
+
+        for (enum_name, members) in &self.enums {
+            for (member_name, value) in members {
+                constants.insert(format!("{enum_name}.{member_name}"), *value);
+            }
+        }
+
+        for ioctl in &mut self.ioctls {
+            ioctl.structs = self.structs.clone();
+            ioctl.constants = constants.clone();
+        }
+    }
+
+    /// Records the line that each input buffer entry is declared on, so that errors building
+    /// the buffer can point at the config. The contents have already been parsed by this
+    /// point, so if the spans can't be read the lines are just left unknown.
+    fn record_entry_lines(&mut self, toml_contents: &str) {
+        let Ok(table) = toml::de::DeTable::parse(toml_contents) else {
+            return;
+        };
+
+        let Some(toml::de::DeValue::Array(ioctl_values)) =
+            table.get_ref().get("ioctls").map(|value| value.get_ref())
+        else {
+            return;
+        };
+
+        let line_of = |position: usize| toml_contents[..position].matches('\n').count() + 1;
+
+        // Lines of each entry in an array of entries, if the value is one
+        let entry_lines =
+            |entries_value: Option<&toml::Spanned<toml::de::DeValue>>| match entries_value
+                .map(|value| value.get_ref())
+            {
+                Some(toml::de::DeValue::Array(entry_values)) => entry_values
+                    .iter()
+                    .map(|entry_value| line_of(entry_value.span().start))
+                    .collect(),
+                _ => Vec::new(),
+            };
+
+        for (ioctl, ioctl_value) in self.ioctls.iter_mut().zip(ioctl_values.iter()) {
+            let toml::de::DeValue::Table(ioctl_table) = ioctl_value.get_ref() else {
+                continue;
+            };
+
+            ioctl.line = Some(line_of(ioctl_value.span().start));
+            ioctl.entry_lines = entry_lines(ioctl_table.get("input_buffer_content"));
+
+            if let Some(toml::de::DeValue::Table(aux_tables)) =
+                ioctl_table.get("aux_buffers").map(|value| value.get_ref())
+            {
+                for (aux_name, aux_value) in aux_tables {
+                    if let Some(aux_buffer) = ioctl.aux_buffers.get_mut(aux_name.get_ref().as_ref())
+                        && let toml::de::DeValue::Table(aux_table) = aux_value.get_ref()
+                    {
+                        aux_buffer.entry_lines = entry_lines(aux_table.get("content"));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes any relative `File` entry paths relative to the config file's directory
+    /// rather than the current working directory.
+    fn resolve_file_paths(&mut self, config_dir: &Path) {
+        for template in self.structs.values_mut() {
+            for field in &mut template.fields {
+                field.entry_data.resolve_file_paths(config_dir);
+            }
+        }
+
+        for ioctl in &mut self.ioctls {
+            let aux_contents = ioctl.aux_buffers.values_mut().map(|aux| &mut aux.content);
+
+            for entry in std::iter::once(&mut ioctl.input_buffer_content)
+                .chain(aux_contents)
+                .flatten()
+                .flatten()
+            {
+                entry.entry_data.resolve_file_paths(config_dir);
+            }
+        }
+    }
+
+    /// Prints the ioctls on the Config struct
+    pub fn print_inputs(&self) {
+        for (i, ioctl) in self.ioctls.iter().enumerate() {
+            println!(
+                "[{i}]: {}(0x{:X}) {}",
+                ioctl.name,
+                ioctl.code,
+                ioctl.code_parts()
+            );
+        }
+    }
+}
+
+impl Ioctl {
+    /// Name of the config file that the IOCTL was declared in, for error messages
+    fn config_file_name(&self) -> String {
+        match &self.config_file {
+            Some(config_file) => config_file.display().to_string(),
+            None => "the config".to_string(),
+        }
+    }
+
+    /// Config file and line that the IOCTL was declared on, for error messages
+    fn declared_at(&self) -> String {
+        match self.line {
+            Some(line) => format!("{} line {line}", self.config_file_name()),
+            None => self.config_file_name(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Path of the device that the IOCTL is sent to. This is only filled in once the
+    /// config has been built, so is empty for an Ioctl that was deserialized on its own.
+    pub fn device_name(&self) -> &str {
+        self.device_name.as_deref().unwrap_or_default()
+    }
+
+    /// How to open the device when sending the IOCTL
+    pub fn open_options(&self) -> &OpenOptions {
+        &self.open_options
+    }
+
+    /// Access masks to replay the IOCTL with: the defaults, and the IOCTL's own if it isn't
+    /// one of them
+    pub fn access_matrix(&self) -> Vec<u32> {
+        let mut access_masks = open_options::DEFAULT_ACCESS_MATRIX.to_vec();
+
+        let desired_access = self.open_options.desired_access_or_default();
+        if !access_masks.contains(&desired_access) {
+            access_masks.push(desired_access);
+        }
+
+        access_masks
+    }
+
+    /// The IOCTL code split into its `CTL_CODE` parts
+    pub fn ctl_code(&self) -> CtlCode {
+        CtlCode::decode(self.code)
+    }
+
+    /// Whether the IOCTL is sent to a Linux device node, e.g. `/dev/ptmx`, rather than a
+    /// Windows device, going by the device path
+    pub fn is_linux_device(&self) -> bool {
+        self.device_name().starts_with('/')
+    }
+
+    /// The IOCTL code split into the parts it is made of on the platform of its device
+    fn code_parts(&self) -> String {
+        match self.is_linux_device() {
+            true => IocCode::decode(self.code).to_string(),
+            false => self.ctl_code().to_string(),
+        }
+    }
+
+    /// Iterates over any input buffer content entries on the Config struct and
+    /// uses them to construct an input buffer of type Vec<u8> that can be used
+    /// in dispatch calls.
+    ///
+    /// Overlapping entries are an error in strict mode, and are otherwise allowed. See
+    /// `find_overlaps` to list them.
+    ///
+    /// Pointers to auxiliary buffers are written as if the buffers were at address 0, as
+    /// they are only allocated when the IOCTL is sent. See `prepare_buffers`.
+    pub fn build_input_buffer(&self) -> Result<Vec<u8>, BufferError> {
+        self.build_buffer(
+            &self.input_spec(),
+            &self.unallocated_addresses(),
+            &mut Vec::new(),
+        )
+    }
+
+    /// Allocates and fills in every buffer needed to send the IOCTL. The auxiliary buffers
+    /// are allocated first, so that pointers to them can be written into the other buffers.
+    pub fn prepare_buffers(&self) -> Result<PreparedBuffers, DispatchError> {
+        let mut aux = HashMap::new();
+        for (aux_name, aux_buffer) in &self.aux_buffers {
+            aux.insert(
+                aux_name.clone(),
+                IoBuffer::zeroed(aux_buffer.size, self.guard_page)
+                    .map_err(DispatchError::Allocation)?,
+            );
+        }
+
+        let hostile = HostileMemory::new().map_err(DispatchError::Allocation)?;
+
+        let addresses = Addresses {
+            aux: self
+                .aux_buffers
+                .keys()
+                .map(|aux_name| (aux_name.as_str(), aux[aux_name].as_ptr() as u64))
+                .collect(),
+            hostile: Some(&hostile),
+        };
+
+        let mut overlaps = Vec::new();
+
+        for (aux_name, aux_buffer) in &self.aux_buffers {
+            let bytes = self.build_buffer(
+                &self.aux_spec(aux_name, aux_buffer),
+                &addresses,
+                &mut overlaps,
+            )?;
+            aux.get_mut(aux_name).unwrap().copy_from_slice(&bytes);
+        }
+
+        let input = match self.null_input {
+            true => None,
+            false => {
+                let mut buffer =
+                    self.build_buffer(&self.input_spec(), &addresses, &mut overlaps)?;
+                buffer.resize(self.allocated_input_buffer_size(), 0);
+
+                Some(self.allocate_buffer(&buffer)?)
+            }
+        };
+
+        Ok(PreparedBuffers {
+            input,
+            output: self.allocate_output_buffer()?,
+            aux,
+            hostile,
+            overlaps,
+        })
+    }
+
+    /// Lays out the entries of one of the buffers and writes them into a zeroed buffer,
+    /// using `addresses` for any pointers. Overlapping entries are added to `overlaps`.
+    fn build_buffer(
+        &self,
+        spec: &BufferSpec,
+        addresses: &Addresses,
+        overlaps: &mut Vec<BufferError>,
+    ) -> Result<Vec<u8>, BufferError> {
+        let mut buffer = vec![0; spec.size];
+
+        let laid_out_entries = self.lay_out_entries(spec, addresses)?;
+
+        let mut buffer_overlaps = find_overlaps(&laid_out_entries).into_iter().map(|overlap| {
+            self.buffer_error(
+                spec,
+                overlap.second,
+                BufferErrorKind::Overlap(Box::new(overlap)),
+            )
+        });
+
+        if self.strict
+            && let Some(overlap) = buffer_overlaps.next()
+        {
+            return Err(overlap);
+        }
+
+        overlaps.extend(buffer_overlaps);
+
+        for entry in laid_out_entries {
+            let destination = &mut buffer[entry.offset..entry.offset + entry.bytes.len()];
+
+            if entry.bitwise {
+                for (byte, entry_byte) in destination.iter_mut().zip(entry.bytes) {
+                    *byte |= entry_byte;
+                }
+            } else {
+                destination.copy_from_slice(&entry.bytes);
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    /// Allocates a zeroed output buffer, or returns None if a NULL output buffer should be
+    /// passed instead.
+    pub fn allocate_output_buffer(&self) -> Result<Option<IoBuffer>, DispatchError> {
+        if self.null_output {
+            return Ok(None);
+        }
+
+        let buffer = IoBuffer::zeroed(self.allocated_output_buffer_size(), self.guard_page)
+            .map_err(DispatchError::Allocation)?;
+
+        Ok(Some(buffer))
+    }
+
+    /// Copies bytes into a buffer allocated with this IOCTL's guard page setting
+    pub fn allocate_buffer(&self, bytes: &[u8]) -> Result<IoBuffer, DispatchError> {
+        IoBuffer::from_slice(bytes, self.guard_page).map_err(DispatchError::Allocation)
+    }
+
+    /// Whether the buffers are placed against a guard page, so that access violations
+    /// reported by the driver are findings
+    pub fn uses_guard_page(&self) -> bool {
+        self.guard_page != GuardPage::None
+    }
+
+    /// Input buffer length that is passed to DeviceIoControl
+    pub fn declared_input_buffer_size(&self) -> usize {
+        self.declared_input_buffer_size
+            .unwrap_or(self.input_buffer_size)
+    }
+
+    /// Input buffer length that is passed to DeviceIoControl for a fuzzed input of
+    /// `input_len` bytes. Mutations change the length of the input, so its own length is
+    /// passed, as a fixed size would send the driver (or the I/O manager) past the end of
+    /// shorter inputs and into the guard page. The declared size is only kept when the
+    /// config sets it apart from the allocated size on purpose.
+    pub fn fuzzed_input_buffer_size(&self, input_len: usize) -> usize {
+        match self.declared_input_buffer_size() == self.allocated_input_buffer_size() {
+            true => input_len,
+            false => self.declared_input_buffer_size(),
+        }
+    }
+
+    /// Number of bytes allocated for the input buffer
+    pub fn allocated_input_buffer_size(&self) -> usize {
+        self.allocated_input_buffer_size
+            .unwrap_or(self.input_buffer_size)
+    }
+
+    /// Output buffer length that is passed to DeviceIoControl
+    pub fn declared_output_buffer_size(&self) -> usize {
+        self.declared_output_buffer_size
+            .unwrap_or(self.output_buffer_size)
+    }
+
+    /// Number of bytes allocated for the output buffer
+    pub fn allocated_output_buffer_size(&self) -> usize {
+        self.allocated_output_buffer_size
+            .unwrap_or(self.output_buffer_size)
+    }
+
+    /// Returns any input buffer entries that write to the same bytes. Entries that are ORed
+    /// into the buffer, such as `Flags` and `Bits`, are expected to overlap and are ignored.
+    pub fn find_overlaps(&self) -> Result<Vec<Overlap>, BufferError> {
+        Ok(find_overlaps(&self.lay_out_entries(
+            &self.input_spec(),
+            &self.unallocated_addresses(),
+        )?))
+    }
+
+    /// Returns a map of which input buffer bytes are set by entries, followed by a list of
+    /// the gaps that are left unset.
+    pub fn coverage_map(&self) -> Result<String, BufferError> {
+        const BYTES_PER_ROW: usize = 16;
+
+        // Number of non-bitwise entries writing each byte, and whether anything writes it
+        let mut writers = vec![0usize; self.input_buffer_size];
+        let mut set = vec![false; self.input_buffer_size];
+
+        for entry in self.lay_out_entries(&self.input_spec(), &self.unallocated_addresses())? {
+            for byte in entry.offset..entry.offset + entry.bytes.len() {
+                set[byte] = true;
+                if !entry.bitwise {
+                    writers[byte] += 1;
+                }
+            }
+        }
+
+        let mut map = format!(
+            "{} (0x{:X} bytes): '#' set, 'X' overlapping, '.' unset\n",
+            self.name, self.input_buffer_size
+        );
+
+        for row_start in (0..self.input_buffer_size).step_by(BYTES_PER_ROW) {
+            let row_end = (row_start + BYTES_PER_ROW).min(self.input_buffer_size);
+            map.push_str(&format!("0x{row_start:04X} "));
+
+            for byte in row_start..row_end {
+                if (byte - row_start) % 8 == 0 {
+                    map.push(' ');
+                }
+
+                map.push(match (writers[byte], set[byte]) {
+                    (2.., _) => 'X',
+                    (_, true) => '#',
+                    (_, false) => '.',
+                });
+            }
+
+            map.push('\n');
+        }
+
+        let mut gaps = Vec::new();
+        let mut byte = 0;
+        while byte < self.input_buffer_size {
+            if set[byte] {
+                byte += 1;
+                continue;
+            }
+
+            let gap_start = byte;
+            while byte < self.input_buffer_size && !set[byte] {
+                byte += 1;
+            }
+            gaps.push((gap_start, byte));
+        }
+
+        if gaps.is_empty() {
+            map.push_str("No unset gaps\n");
+        } else {
+            map.push_str("Unset gaps:\n");
+            for (gap_start, gap_end) in gaps {
+                map.push_str(&format!(
+                    "  0x{gap_start:X}..0x{gap_end:X} (0x{:X} bytes)\n",
+                    gap_end - gap_start
+                ));
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Expands and lays out every entry of one of the buffers, resolving any computed
+    /// entries, and returns the bytes for each along with where they should be written.
+    fn lay_out_entries(
+        &self,
+        spec: &BufferSpec,
+        addresses: &Addresses,
+    ) -> Result<Vec<LaidOutEntry>, BufferError> {
+        let mut errors = Vec::new();
+        let laid_out_entries = self.lay_out_valid_entries(spec, addresses, &mut errors);
+
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(laid_out_entries),
+        }
+    }
+
+    /// Lays out the entries of one of the buffers like `lay_out_entries`, but carries on
+    /// past entries that can't be laid out, adding the first error for each of them to
+    /// `errors` and leaving them out, so that every problem can be reported at once.
+    fn lay_out_valid_entries(
+        &self,
+        spec: &BufferSpec,
+        addresses: &Addresses,
+        errors: &mut Vec<BufferError>,
+    ) -> Vec<LaidOutEntry> {
+        let mut failed = vec![false; spec.content.len()];
+        let mut fail = |index: usize, kind: BufferErrorKind| {
+            if !std::mem::replace(&mut failed[index], true) {
+                errors.push(self.buffer_error(spec, index, kind));
+            }
+        };
+
+        let mut expansion = Expansion {
+            buffer_size: spec.size,
+            ..Default::default()
+        };
+
+        for (index, entry) in spec.content.iter().enumerate() {
+            let entries_len = expansion.entries.len();
+            let instances_len = expansion.instances.len();
+
+            expansion.index = index;
+            expansion.part.clear();
+
+            if let Err(kind) = self.expand_entry(
+                entry.name.as_ref(),
+                entry.offset,
+                &entry.entry_data,
+                "",
+                0,
+                &mut expansion,
+            ) {
+                // Drop whatever was expanded before the error
+                expansion.entries.truncate(entries_len);
+                expansion.instances.truncate(instances_len);
+                fail(index, kind);
+            }
+        }
+
+        let Expansion {
+            entries, instances, ..
+        } = expansion;
+
+        // Lay out every entry before writing anything, so that computed entries can refer to
+        // entries regardless of the order they appear in the config
+        let mut entries_bytes = Vec::with_capacity(entries.len());
+        let mut layout: HashMap<&str, EntryLayout> = HashMap::new();
+
+        for entry in &entries {
+            let entry_bytes = entry
+                .entry_data
+                .sized_by_config()
+                .map_err(BufferErrorKind::from)
+                .and_then(|size| match size {
+                    Some(size) => check_buffer_overwrite(entry.offset, size, spec.size),
+                    None => Ok(()),
+                })
+                .and_then(|()| Ok(self.entry_bytes(entry.entry_data)?))
+                .and_then(|entry_bytes| {
+                    check_buffer_overwrite(entry.offset, entry_bytes.len(), spec.size)?;
+                    Ok(entry_bytes)
+                });
+
+            // Failed entries keep an empty place, so that instances can still find theirs
+            let entry_bytes = match entry_bytes {
+                Ok(entry_bytes) => entry_bytes,
+                Err(kind) => {
+                    fail(entry.index, kind);
+                    Vec::new()
+                }
+            };
+
+            if let Some(name) = &entry.name {
+                let entry_layout = EntryLayout {
+                    offset: entry.offset,
+                    size: entry_bytes.len(),
+                };
+
+                if layout.contains_key(name.as_str()) {
+                    fail(entry.index, "Duplicate input buffer entry name".into());
+                } else {
+                    layout.insert(name, entry_layout);
+                }
+            }
+
+            entries_bytes.push(entry_bytes);
+        }
+
+        // Nested instances are always expanded before the instance containing them, so their
+        // sizes are known by the time the containing instance's size is worked out
+        let mut instance_ends: Vec<usize> = Vec::with_capacity(instances.len());
+
+        for instance in &instances {
+            let size = instance.size.unwrap_or_else(|| {
+                let entry_ends = entries[instance.entries.clone()]
+                    .iter()
+                    .zip(&entries_bytes[instance.entries.clone()])
+                    .map(|(entry, entry_bytes)| entry.offset + entry_bytes.len());
+                let nested_ends = instance_ends[instance.instances.clone()].iter().copied();
+
+                entry_ends
+                    .chain(nested_ends)
+                    .max()
+                    .map_or(0, |end| end - instance.offset)
+            });
+            instance_ends.push(instance.offset + size);
+
+            let instance_layout = EntryLayout {
+                offset: instance.offset,
+                size,
+            };
+
+            if layout.contains_key(instance.name.as_str()) {
+                fail(instance.index, "Duplicate input buffer entry name".into());
+            } else {
+                layout.insert(&instance.name, instance_layout);
+            }
+        }
+
+        for (entry, entry_bytes) in entries.iter().zip(entries_bytes.iter_mut()) {
+            if entry.entry_data.computed_width().is_some() {
+                // Targets are looked up within the entry's own struct instance first, so that
+                // templates can refer to their own fields
+                let find_target = |target: &str| {
+                    layout
+                        .get(format!("{}{target}", entry.scope).as_str())
+                        .or_else(|| layout.get(target))
+                        .copied()
+                };
+
+                match entry
+                    .entry_data
+                    .resolve_computed(find_target, addresses, spec.size)
+                {
+                    Ok(resolved_bytes) => *entry_bytes = resolved_bytes,
+                    Err(reason) => fail(entry.index, reason.into()),
+                }
+            }
+        }
+
+        entries
+            .iter()
+            .zip(entries_bytes)
+            .filter(|(entry, _)| !failed[entry.index])
+            .map(|(entry, bytes)| LaidOutEntry {
+                index: entry.index,
+                part: entry.part.clone(),
+                offset: entry.offset,
+                bytes,
+                bitwise: entry.entry_data.is_bitwise(),
+            })
+            .collect()
+    }
+
+    /// Flattens an entry into the entries that will actually be written, expanding any
+    /// struct, repeat and array entries. Offsets are made relative to the start of the
+    /// buffer, and names are qualified with the scope of the instance they sit in.
+    fn expand_entry<'a>(
+        &'a self,
+        name: Option<&String>,
+        offset: usize,
+        entry_data: &'a EntryData,
+        scope: &str,
+        depth: usize,
+        expansion: &mut Expansion<'a>,
+    ) -> Result<(), BufferErrorKind> {
+        let qualified_name = name.map(|name| format!("{scope}{name}"));
+
+        if !matches!(
+            entry_data,
+            EntryData::Struct { .. } | EntryData::Repeat { .. } | EntryData::Array { .. }
+        ) {
+            expansion.entries.push(ExpandedEntry {
+                index: expansion.index,
+                part: expansion.part.clone(),
+                name: qualified_name,
+                scope: scope.to_string(),
+                offset,
+                entry_data,
+            });
+
+            return Ok(());
+        }
+
+        if depth >= MAX_NESTING_DEPTH {
+            return Err("Input buffer entries are nested too deeply".into());
+        }
+
+        // Unnamed instances still get their own scope, so that their fields don't clash with
+        // those of other instances
+        let instance_scope = match &qualified_name {
+            Some(qualified_name) => qualified_name.clone(),
+            None => format!("{scope}[{offset:#x}]"),
+        };
+        let first_entry = expansion.entries.len();
+        let first_instance = expansion.instances.len();
+
+        let size = match entry_data {
+            EntryData::Struct {
+                struct_name,
+                overrides,
+            } => {
+                let template = self
+                    .structs
+                    .get(struct_name)
+                    .ok_or("Unknown struct template")?;
+
+                for field_name in overrides.keys() {
+                    if !template
+                        .fields
+                        .iter()
+                        .any(|field| field.name.as_ref() == Some(field_name))
+                    {
+                        return Err("Struct override refers to an unknown field".into());
+                    }
+                }
+
+                let field_scope = format!("{instance_scope}.");
+
+                for (field_index, field) in template.fields.iter().enumerate() {
+                    let field_data = field
+                        .name
+                        .as_ref()
+                        .and_then(|field_name| overrides.get(field_name))
+                        .unwrap_or(&field.entry_data);
+
+                    let field_part = match &field.name {
+                        Some(field_name) => field_name.clone(),
+                        None => format!("fields[{field_index}]"),
+                    };
+
+                    let part_len = expansion.enter_part(&field_part);
+                    self.expand_entry(
+                        field.name.as_ref(),
+                        offset_add(offset, field.offset)?,
+                        field_data,
+                        &field_scope,
+                        depth + 1,
+                        expansion,
+                    )?;
+                    expansion.part.truncate(part_len);
+                }
+
+                template.size
+            }
+            EntryData::Repeat {
+                count,
+                stride,
+                entries,
+            } => {
+                // Records that overlap, or all sit at the same offset, would let a huge count
+                // through the size check below
+                if *count > 0 {
+                    if *stride == 0 {
+                        return Err("Repeat stride must not be 0".into());
+                    }
+
+                    let record_size = entries.iter().try_fold(0, |record_size, entry| {
+                        let entry_end = offset_add(
+                            entry.offset,
+                            self.entry_size(&entry.entry_data, depth + 1)?,
+                        )?;
+
+                        Ok::<_, &'static str>(entry_end.max(record_size))
+                    })?;
+
+                    if *stride < record_size {
+                        return Err("Repeat stride is smaller than its records".into());
+                    }
+                }
+
+                // Check the whole table fits before expanding it, as huge counts are expected
+                // when bounds-check testing
+                let size = count
+                    .checked_mul(*stride)
+                    .ok_or("Input buffer entry content is out of bounds")?;
+                check_buffer_overwrite(offset, size, expansion.buffer_size)?;
+
+                for record in 0..*count {
+                    let record_offset = offset + record * stride;
+                    let record_scope = format!("{instance_scope}[{record}].");
+                    let record_part_len = expansion.enter_part(&format!("[{record}]"));
+
+                    for (entry_index, entry) in entries.iter().enumerate() {
+                        let entry_part = match &entry.name {
+                            Some(entry_name) => entry_name.clone(),
+                            None => format!("entries[{entry_index}]"),
+                        };
+
+                        let part_len = expansion.enter_part(&entry_part);
+                        self.expand_entry(
+                            entry.name.as_ref(),
+                            offset_add(record_offset, entry.offset)?,
+                            &entry.entry_data,
+                            &record_scope,
+                            depth + 1,
+                            expansion,
+                        )?;
+                        expansion.part.truncate(part_len);
+                    }
+
+                    expansion.part.truncate(record_part_len);
+                }
+
+                Some(size)
+            }
+            EntryData::Array { values, stride } => {
+                let mut element_offset = offset;
+
+                for (index, value) in values.iter().enumerate() {
+                    let part_len = expansion.enter_part(&format!("[{index}]"));
+                    self.expand_entry(
+                        Some(&format!("[{index}]")),
+                        element_offset,
+                        value,
+                        &instance_scope,
+                        depth + 1,
+                        expansion,
+                    )?;
+                    expansion.part.truncate(part_len);
+
+                    let element_size = match stride {
+                        Some(stride) => *stride,
+                        None => self.entry_size(value, depth + 1)?,
+                    };
+                    element_offset = offset_add(element_offset, element_size)?;
+                }
+
+                Some(element_offset - offset)
+            }
+            _ => unreachable!("only container entries are expanded"),
+        };
+
+        if let Some(qualified_name) = qualified_name {
+            expansion.instances.push(ExpandedInstance {
+                index: expansion.index,
+                name: qualified_name,
+                offset,
+                size,
+                entries: first_entry..expansion.entries.len(),
+                instances: first_instance..expansion.instances.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Encodes a single expanded entry. Computed entries are left zeroed until every other
+    /// entry has been laid out.
+    fn entry_bytes(&self, entry_data: &EntryData) -> Result<Vec<u8>, &'static str> {
+        match entry_data {
+            EntryData::Flags {
+                value,
+                width,
+                endian,
+            } => width.encode(self.resolve_flags(value)?, *endian),
+            entry_data => match entry_data.computed_width() {
+                Some(width) => Ok(vec![0; width]),
+                None => entry_data.to_bytes(),
+            },
+        }
+    }
+
+    /// Adds the IOCTL, buffer and config line to an error from one of a buffer's entries
+    fn buffer_error(
+        &self,
+        spec: &BufferSpec,
+        entry: usize,
+        kind: impl Into<BufferErrorKind>,
+    ) -> BufferError {
+        BufferError {
+            ioctl: self.name.clone(),
+            aux_buffer: spec.aux_name.map(str::to_string),
+            entry,
+            file: self.config_file.as_deref().map(Box::from),
+            line: spec.entry_lines.get(entry).copied(),
+            kind: kind.into(),
+        }
+    }
+
+    fn input_spec(&self) -> BufferSpec<'_> {
+        BufferSpec {
+            aux_name: None,
+            size: self.input_buffer_size,
+            content: self.input_buffer_content.as_deref().unwrap_or_default(),
+            entry_lines: &self.entry_lines,
+        }
+    }
+
+    fn aux_spec<'a>(&'a self, aux_name: &'a str, aux_buffer: &'a AuxBuffer) -> BufferSpec<'a> {
+        BufferSpec {
+            aux_name: Some(aux_name),
+            size: aux_buffer.size,
+            content: aux_buffer.content.as_deref().unwrap_or_default(),
+            entry_lines: &aux_buffer.entry_lines,
+        }
+    }
+
+    /// Addresses used for pointers when building buffers without allocating them
+    fn unallocated_addresses(&self) -> Addresses<'_> {
+        Addresses {
+            aux: self
+                .aux_buffers
+                .keys()
+                .map(|aux_name| (aux_name.as_str(), 0))
+                .collect(),
+            hostile: None,
+        }
+    }
+
+    /// ORs together the values of the named constants and enum members in a `Flags` entry
+    fn resolve_flags(&self, names: &[String]) -> Result<u64, &'static str> {
+        names.iter().try_fold(0, |flags, name| {
+            let value = self
+                .constants
+                .get(name)
+                .ok_or("Flags entry refers to an unknown constant")?;
+
+            Ok(flags | value)
+        })
+    }
+
+    /// Works out the size of an entry without laying it out, for packing array elements
+    fn entry_size(&self, entry_data: &EntryData, depth: usize) -> Result<usize, &'static str> {
+        if depth >= MAX_NESTING_DEPTH {
+            return Err("Input buffer entries are nested too deeply");
+        }
+
+        if let Some(width) = entry_data.computed_width() {
+            return Ok(width);
+        }
+
+        if let Some(size) = entry_data.sized_by_config()? {
+            return Ok(size);
+        }
+
+        match entry_data {
+            EntryData::Struct { struct_name, .. } => self
+                .structs
+                .get(struct_name)
+                .ok_or("Unknown struct template")?
+                .size
+                .ok_or(
+                    "Struct templates used in a Repeat, or an Array without a stride, need a size",
+                ),
+            EntryData::Repeat { count, stride, .. } => count
+                .checked_mul(*stride)
+                .ok_or("Input buffer entry content is out of bounds"),
+            EntryData::Array { values, stride } => match stride {
+                Some(stride) => values
+                    .len()
+                    .checked_mul(*stride)
+                    .ok_or("Input buffer entry content is out of bounds"),
+                None => values.iter().try_fold(0usize, |size, value| {
+                    offset_add(size, self.entry_size(value, depth + 1)?)
+                }),
+            },
+            EntryData::Flags { width, .. } => Ok(width.0),
+            _ => Ok(entry_data.to_bytes()?.len()),
+        }
+    }
+}
+
+/// Finds every pair of laid out entries that write to the same bytes, ignoring entries
+/// that are ORed into the buffer
+fn find_overlaps(entries: &[LaidOutEntry]) -> Vec<Overlap> {
+    let mut ranges: Vec<(usize, usize, &LaidOutEntry)> = entries
+        .iter()
+        .filter(|entry| !entry.bitwise && !entry.bytes.is_empty())
+        .map(|entry| (entry.offset, entry.offset + entry.bytes.len(), entry))
+        .collect();
+    ranges.sort_by_key(|(start, end, entry)| (*start, *end, entry.index));
+
+    let mut overlaps = Vec::new();
+
+    for (i, (first_start, first_end, first_entry)) in ranges.iter().enumerate() {
+        for (second_start, second_end, second_entry) in &ranges[i + 1..] {
+            if second_start >= first_end {
+                break;
+            }
+
+            // Entries are listed in config order, and parts of the same entry in buffer order
+            let (first, second) = match second_entry.index < first_entry.index {
+                true => (second_entry, first_entry),
+                false => (first_entry, second_entry),
+            };
+
+            overlaps.push(Overlap {
+                first: first.index,
+                first_part: first.part.clone(),
+                second: second.index,
+                second_part: second.part.clone(),
+                start: *second_start.max(first_start),
+                end: *second_end.min(first_end),
+            });
+        }
+    }
+
+    overlaps
+}
+
+/// Bit after the last bit of a `Bits` entry, treating overflow as out of bounds
+fn bits_end(bit_offset: usize, bit_width: usize) -> Result<usize, &'static str> {
+    bit_offset
+        .checked_add(bit_width)
+        .ok_or("Input buffer entry content is out of bounds")
+}
+
+/// Adds a relative offset onto a base offset, treating overflow as out of bounds
+fn offset_add(base: usize, offset: usize) -> Result<usize, &'static str> {
+    base.checked_add(offset)
+        .ok_or("Input buffer entry content is out of bounds")
+}
+
+/// Helper function to check that a buffer content entry does not exceed the buffer
+/// size. This would error anyway due to Rust's bounds checking, but we handle anyway to
+/// inform the user.
+fn check_buffer_overwrite(
+    offset: usize,
+    entry_size: usize,
+    buffer_size: usize,
+) -> Result<(), BufferErrorKind> {
+    if offset
+        .checked_add(entry_size)
+        .is_none_or(|end| end > buffer_size)
+    {
+        return Err(BufferErrorKind::OutOfBounds {
+            offset,
+            size: entry_size,
+            buffer_size,
+        });
+    }
+    Ok(())
+}
+
+/// Runs the dispatcher, and returns the outcome of each IOCTL it sent.
+pub fn send_single(dispatcher: &impl Dispatcher) -> Result<Vec<DispatchOutcome>, DispatchError> {
+    let outcomes = Mutex::new(Vec::new());
+    dispatcher.dispatch(&outcomes)?;
+
+    Ok(outcomes.into_inner().unwrap())
+}
+
+/// Launches num_threads number of threads and runs the same dispatcher in each one.
+pub fn fuzz_single<D, R>(
+    dispatcher: D,
+    num_threads: u32,
+    reporter: Arc<R>,
+) -> Result<(), DispatchError>
+where
+    // TODO: This presumably is not the right way to do this
+    D: Dispatcher + Send + Sync + Clone + 'static,
+    R: Reporter + 'static,
+{
+    dispatch_in_threads((0..num_threads).map(|_| dispatcher.clone()), reporter)
+}
+
+pub fn fuzz_multiple<D, R>(dispatchers: Vec<D>, reporter: Arc<R>) -> Result<(), DispatchError>
+where
+    D: Dispatcher + Send + Sync + Clone + 'static,
+    R: Reporter + 'static,
+{
+    dispatch_in_threads(dispatchers, reporter)
+}
+
+/// Runs each dispatcher in its own thread. Fuzzing threads run until they hit an error, so
+/// the first error from any thread is returned straight away rather than waiting for the
+/// rest to finish.
+fn dispatch_in_threads<D, R>(
+    dispatchers: impl IntoIterator<Item = D>,
+    reporter: Arc<R>,
+) -> Result<(), DispatchError>
+where
+    D: Dispatcher + Send + 'static,
+    R: Reporter + 'static,
+{
+    let (error_sender, error_receiver) = mpsc::channel();
+    let mut handles = vec![];
+
+    for dispatcher in dispatchers {
+        let error_sender = error_sender.clone();
+        let reporter = Arc::clone(&reporter);
+
+        let handle = thread::spawn(move || {
+            if let Err(e) = dispatcher.dispatch(reporter.as_ref()) {
+                // The receiver only goes away once an error has already been returned
+                let _ = error_sender.send(e);
+            }
+        });
+
+        handles.push(handle);
+    }
+
+    // Only the threads hold senders now, so this returns once they have all finished
+    drop(error_sender);
+    if let Ok(e) = error_receiver.recv() {
+        return Err(e);
+    }
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_build_correct_cmdline_args() {
+        let args: Vec<String> = vec!["ioctiller.exe".to_string(), "C:\\test.toml".to_string()];
+
+        let cli = Cli::build(&args).unwrap();
+        let correct_path = std::path::PathBuf::from("C:\\test.toml");
+
+        assert_eq!(correct_path, cli.file_path);
+        assert!(!cli.validate);
+    }
+
+    #[test]
+    fn cli_build_validate_command() {
+        let args: Vec<String> = vec![
+            "ioctiller.exe".to_string(),
+            "validate".to_string(),
+            "C:\\test.toml".to_string(),
+        ];
+
+        let cli = Cli::build(&args).unwrap();
+
+        assert_eq!(std::path::PathBuf::from("C:\\test.toml"), cli.file_path);
+        assert!(cli.validate);
+    }
+
+    #[test]
+    fn cli_build_format_flag() {
+        let args: Vec<String> = vec![
+            "ioctiller.exe".to_string(),
+            "validate".to_string(),
+            "--format".to_string(),
+            "yaml".to_string(),
+            "C:\\test.cfg".to_string(),
+        ];
+
+        let cli = Cli::build(&args).unwrap();
+
+        assert_eq!(std::path::PathBuf::from("C:\\test.cfg"), cli.file_path);
+        assert!(cli.validate);
+        assert_eq!(Some(ConfigFormat::Yaml), cli.format);
+
+        let args: Vec<String> = vec![
+            "ioctiller.exe".to_string(),
+            "C:\\test.cfg".to_string(),
+            "--format".to_string(),
+            "xml".to_string(),
+        ];
+
+        assert!(Cli::build(&args).is_err());
+    }
+
+    #[test]
+    fn cli_build_no_cmdline_args() {
+        let args: Vec<String> = vec!["ioctiller.exe".to_string()];
+
+        assert!(Cli::build(&args).is_err());
+    }
+
+    #[test]
+    fn cli_build_too_many_cmdline_args() {
+        let args: Vec<String> = vec![
+            "iotctiller.exe".to_string(),
+            "C:\\test.toml".to_string(),
+            "foobar".to_string(),
+        ];
+
+        assert!(Cli::build(&args).is_err());
+    }
+
+    #[test]
+    fn build_buffer_success() {
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x70,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::U32 {
+                        value: 0x1337C0DE,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x10,
+                    entry_data: EntryData::U64 {
+                        value: 0xDEADBEEFCAFEBABE,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x20,
+                    entry_data: EntryData::U8 { value: 0x41 },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x28,
+                    entry_data: EntryData::U16 {
+                        value: 0x5A4D,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x30,
+                    entry_data: EntryData::String8 {
+                        value: "foobar".to_string(),
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x40,
+                    entry_data: EntryData::Fill {
+                        value: 0x24,
+                        length: 0x30,
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let correct_buffer = vec![
+            0xDE, 0xC0, 0x37, 0x13, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+            0xBE, 0xBA, 0xFE, 0xCA, 0xEF, 0xBE, 0xAD, 0xDE, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+            0x41, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x4D, 0x5A, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+            0x66, 0x6f, 0x6f, 0x62, 0x61, 0x72, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+            0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
+            0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
+            0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
+            0x24, 0x24, 0x24, 0x24, 0x24, 0x24,
+        ];
+
+        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_no_entries() {
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x60,
+            output_buffer_size: 0x8,
+            input_buffer_content: None,
+            ..Default::default()
+        };
+
+        let correct_buffer = vec![0; 0x60];
+
+        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_oob() {
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x60,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x60,
+                entry_data: EntryData::U32 {
+                    value: 0x1337C0DE,
+                    endian: Endian::Little,
+                },
+            }]),
+            ..Default::default()
+        };
+
+        assert!(ioctl.build_input_buffer().is_err());
+    }
+
+    #[test]
+    fn build_buffer_huge_fill() {
+        // The length is checked against the buffer before anything is allocated
+        let ioctl = Ioctl {
+            input_buffer_size: 0x60,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x10,
+                entry_data: EntryData::Fill {
+                    value: 0x41,
+                    length: 0x7FFFFFFFFFFF,
+                },
+            }]),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            ioctl.build_input_buffer().unwrap_err().kind,
+            BufferErrorKind::OutOfBounds {
+                offset: 0x10,
+                size: 0x7FFFFFFFFFFF,
+                buffer_size: 0x60
+            }
+        ));
+    }
+
+    #[test]
+    fn build_buffer_signed_and_float() {
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x20,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::I8 { value: -1 },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x2,
+                    entry_data: EntryData::I16 {
+                        value: -2,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x4,
+                    entry_data: EntryData::I32 {
+                        value: -0x100,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x8,
+                    entry_data: EntryData::I64 {
+                        value: i64::MIN,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x10,
+                    entry_data: EntryData::F32 {
+                        value: 1.5,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x18,
+                    entry_data: EntryData::F64 {
+                        value: -2.0,
+                        endian: Endian::Little,
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let correct_buffer = vec![
+            0xFF, 0x0, 0xFE, 0xFF, 0x0, 0xFF, 0xFF, 0xFF, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x80,
+            0x0, 0x0, 0xC0, 0x3F, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0xC0,
+        ];
+
+        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_big_endian() {
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x18,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::U16 {
+                        value: 0x0800,
+                        endian: Endian::Big,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x4,
+                    entry_data: EntryData::I32 {
+                        value: -2,
+                        endian: Endian::Big,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x8,
+                    entry_data: EntryData::U64 {
+                        value: 0x1122334455667788,
+                        endian: Endian::Big,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x10,
+                    entry_data: EntryData::F32 {
+                        value: 1.5,
+                        endian: Endian::Big,
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let correct_buffer = vec![
+            0x08, 0x0, 0x0, 0x0, 0xFF, 0xFF, 0xFF, 0xFE, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+            0x88, 0x3F, 0xC0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        ];
+
+        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_string16() {
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x20,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::String16 {
+                        value: "C:\\a".to_string(),
+                        null_terminate: true,
+                        pad_to: None,
+                        max_length: None,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x10,
+                    entry_data: EntryData::String16 {
+                        value: "foobar".to_string(),
+                        null_terminate: true,
+                        pad_to: Some(0xC),
+                        max_length: Some(3),
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let correct_buffer = vec![
+            0x43, 0x0, 0x3A, 0x0, 0x5C, 0x0, 0x61, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+            0x66, 0x0, 0x6F, 0x0, 0x6F, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        ];
+
+        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn string16_pad_to_shorter_than_string() {
+        let entry_data = EntryData::String16 {
+            value: "abc".to_string(),
+            null_terminate: false,
+            pad_to: Some(2),
+            max_length: None,
+        };
+
+        assert_eq!(
+            vec![0x61, 0x0, 0x62, 0x0, 0x63, 0x0],
+            entry_data.to_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn string16_huge_pad_to() {
+        let mut ioctl = Ioctl {
+            input_buffer_size: 0x20,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x8,
+                entry_data: EntryData::String16 {
+                    value: "abc".to_string(),
+                    null_terminate: true,
+                    pad_to: Some(0x7FFFFFFFFFFF),
+                    max_length: None,
+                },
+            }]),
+            ..Default::default()
+        };
+
+        // The padded length is checked against the buffer before anything is allocated
+        assert!(matches!(
+            ioctl.build_input_buffer().unwrap_err().kind,
+            BufferErrorKind::OutOfBounds {
+                offset: 0x8,
+                size: 0x7FFFFFFFFFFF,
+                buffer_size: 0x20
+            }
+        ));
+
+        // A string longer than pad_to is still checked at its own length
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x18,
+            entry_data: EntryData::String16 {
+                value: "abcd".to_string(),
+                null_terminate: true,
+                pad_to: Some(0x4),
+                max_length: None,
+            },
+        }]);
+        assert!(matches!(
+            ioctl.build_input_buffer().unwrap_err().kind,
+            BufferErrorKind::OutOfBounds { size: 0xA, .. }
+        ));
+    }
+
+    #[test]
+    fn string16_non_bmp() {
+        let entry_data = EntryData::String16 {
+            value: "\u{1F600}".to_string(),
+            null_terminate: false,
+            pad_to: None,
+            max_length: None,
+        };
+
+        assert_eq!(vec![0x3D, 0xD8, 0x00, 0xDE], entry_data.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn build_buffer_raw_bytes() {
+        let file_path = std::env::temp_dir().join("ioctiller_build_buffer_raw_bytes.bin");
+        fs::write(&file_path, [0x10, 0x11, 0x12, 0x13, 0x14, 0x15]).unwrap();
+
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x10,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::Bytes {
+                        value: vec![0x4D, 0x5A, 0x90, 0x00],
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x4,
+                    entry_data: EntryData::Base64 {
+                        value: vec![0x66, 0x6F, 0x6F],
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x8,
+                    entry_data: EntryData::File {
+                        path: file_path.clone(),
+                        file_offset: 0x2,
+                        length: Some(0x3),
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0xC,
+                    entry_data: EntryData::File {
+                        path: file_path.clone(),
+                        file_offset: 0x4,
+                        length: None,
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let correct_buffer = vec![
+            0x4D, 0x5A, 0x90, 0x00, 0x66, 0x6F, 0x6F, 0x0, 0x12, 0x13, 0x14, 0x0, 0x14, 0x15, 0x0,
+            0x0,
+        ];
+
+        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+
+        fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn file_entry_too_short() {
+        let file_path = std::env::temp_dir().join("ioctiller_file_entry_too_short.bin");
+        fs::write(&file_path, [0x41; 4]).unwrap();
+
+        let entry_data = EntryData::File {
+            path: file_path.clone(),
+            file_offset: 0x2,
+            length: Some(0x4),
+        };
+
+        let result = entry_data.to_bytes();
+
+        fs::remove_file(&file_path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn entry_data_deserialize_raw_bytes() {
+        let entry: BufferContentEntry =
+            toml::from_str("offset = 0\ntype = \"Bytes\"\nvalue = \"4d5a 9000 FF\"").unwrap();
+
+        assert_eq!(
+            vec![0x4D, 0x5A, 0x90, 0x00, 0xFF],
+            entry.entry_data.to_bytes().unwrap()
+        );
+
+        let entry: BufferContentEntry =
+            toml::from_str("offset = 0\ntype = \"Base64\"\nvalue = \"TVqQAA==\"").unwrap();
+
+        assert_eq!(
+            vec![0x4D, 0x5A, 0x90, 0x00],
+            entry.entry_data.to_bytes().unwrap()
+        );
+
+        // Odd number of digits, non-hex digits and invalid base64 are rejected on load
+        assert!(
+            toml::from_str::<BufferContentEntry>("offset = 0\ntype = \"Bytes\"\nvalue = \"4d5\"")
+                .is_err()
+        );
+        assert!(
+            toml::from_str::<BufferContentEntry>("offset = 0\ntype = \"Bytes\"\nvalue = \"zz\"")
+                .is_err()
+        );
+        assert!(
+            toml::from_str::<BufferContentEntry>("offset = 0\ntype = \"Base64\"\nvalue = \"%%\"")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn config_resolves_relative_file_paths() {
+        let mut config: Config = toml::from_str(
+            r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = 0x10000
+input_buffer_size = 0x10
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "File", path = "captures/request.bin", file_offset = 0x20 },
+]"#,
+        )
+        .unwrap();
+
+        let config_dir = Path::new("configs");
+        config.resolve_file_paths(config_dir);
+
+        let entries = config.ioctls[0].input_buffer_content.as_ref().unwrap();
+        match &entries[0].entry_data {
+            EntryData::File {
+                path, file_offset, ..
+            } => {
+                assert_eq!(config_dir.join("captures/request.bin"), *path);
+                assert_eq!(0x20, *file_offset);
+            }
+            _ => panic!("Expected File entry"),
+        }
+    }
+
+    #[test]
+    fn config_resolves_relative_file_paths_in_structs() {
+        let mut config: Config = toml::from_str(
+            r#"device_name = '\\.\Test'
+
+[structs.REQUEST]
+fields = [
+    { name = "header", offset = 0x0, type = "File", path = "captures/header.bin" },
+    { name = "body", offset = 0x8, type = "U64", value = 0 },
+]
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = 0x10000
+input_buffer_size = 0x10
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "Struct", struct = "REQUEST", overrides = { body = { type = "File", path = "captures/body.bin" } } },
+]"#,
+        )
+        .unwrap();
+
+        let config_dir = Path::new("configs");
+        config.resolve_file_paths(config_dir);
+
+        match &config.structs["REQUEST"].fields[0].entry_data {
+            EntryData::File { path, .. } => {
+                assert_eq!(config_dir.join("captures/header.bin"), *path)
+            }
+            _ => panic!("Expected File entry"),
+        }
+
+        let entries = config.ioctls[0].input_buffer_content.as_ref().unwrap();
+        match &entries[0].entry_data {
+            EntryData::Struct { overrides, .. } => match &overrides["body"] {
+                EntryData::File { path, .. } => {
+                    assert_eq!(config_dir.join("captures/body.bin"), *path)
+                }
+                _ => panic!("Expected File override"),
+            },
+            _ => panic!("Expected Struct entry"),
+        }
+    }
+
+    #[test]
+    fn build_buffer_computed_entries() {
+        let ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x20,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::BufferSize {
+                        width: IntWidth(4),
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x4,
+                    entry_data: EntryData::SizeOf {
+                        target: "path".to_string(),
+                        width: IntWidth(2),
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x8,
+                    entry_data: EntryData::OffsetOf {
+                        target: "path".to_string(),
+                        width: IntWidth(8),
+                        endian: Endian::Big,
+                    },
+                },
+                BufferContentEntry {
+                    name: Some("path".to_string()),
+                    offset: 0x10,
+                    entry_data: EntryData::String16 {
+                        value: "abc".to_string(),
+                        null_terminate: true,
+                        pad_to: None,
+                        max_length: None,
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let correct_buffer = vec![
+            0x20, 0x0, 0x0, 0x0, 0x8, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x10, 0x61,
+            0x0, 0x62, 0x0, 0x63, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        ];
+
+        let input_buffer: Vec<u8> = ioctl.build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_computed_entry_errors() {
+        let mut ioctl = Ioctl {
+            code: 0x10000,
+            overlapped: false,
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x400,
+            output_buffer_size: 0x8,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x0,
+                entry_data: EntryData::SizeOf {
+                    target: "missing".to_string(),
+                    width: IntWidth(4),
+                    endian: Endian::Little,
+                },
+            }]),
+            ..Default::default()
+        };
+
+        // Unknown target
+        assert!(ioctl.build_input_buffer().is_err());
+
+        // Value does not fit in the entry width
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::BufferSize {
+                width: IntWidth(1),
+                endian: Endian::Little,
+            },
+        }]);
+        assert!(ioctl.build_input_buffer().is_err());
+
+        // Duplicate names
+        ioctl.input_buffer_content = Some(vec![
+            BufferContentEntry {
+                name: Some("a".to_string()),
+                offset: 0x0,
+                entry_data: EntryData::U8 { value: 0x1 },
+            },
+            BufferContentEntry {
+                name: Some("a".to_string()),
+                offset: 0x1,
+                entry_data: EntryData::U8 { value: 0x2 },
+            },
+        ]);
+        assert!(ioctl.build_input_buffer().is_err());
+    }
+
+    #[test]
+    fn entry_data_deserialize_computed() {
+        let entry: BufferContentEntry =
+            toml::from_str("offset = 0\ntype = \"SizeOf\"\ntarget = \"name\"").unwrap();
+
+        match entry.entry_data {
+            EntryData::SizeOf { target, width, .. } => {
+                assert_eq!("name", target);
+                assert_eq!(IntWidth(4), width);
+            }
+            _ => panic!("Expected SizeOf entry"),
+        }
+
+        let entry: BufferContentEntry =
+            toml::from_str("name = \"hdr\"\noffset = 0\ntype = \"U8\"\nvalue = 1").unwrap();
+
+        assert_eq!(Some("hdr".to_string()), entry.name);
+
+        assert!(
+            toml::from_str::<BufferContentEntry>("offset = 0\ntype = \"BufferSize\"\nwidth = 3")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn build_buffer_struct_instances() {
+        let mut config: Config = toml::from_str(
+            r#"device_name = '\\.\Test'
+
+[structs.HEADER]
+size = 0x10
+fields = [
+    { name = "Size", offset = 0x0, type = "SizeOf", target = "Body" },
+    { name = "Flags", offset = 0x4, type = "U32", value = 0x1 },
+    { name = "Body", offset = 0x8, type = "U16", value = 0x4141 },
+]
+
+[structs.OUTER]
+fields = [
+    { name = "Hdr", offset = 0x0, type = "Struct", struct = "HEADER" },
+    { name = "Tail", offset = 0x10, type = "U8", value = 0xFF },
+]
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = 0x10000
+input_buffer_size = 0x40
+output_buffer_size = 0x0
+input_buffer_content = [
+    { name = "first", offset = 0x0, type = "Struct", struct = "HEADER" },
+    { name = "second", offset = 0x10, type = "Struct", struct = "OUTER", overrides = { Tail = { type = "U8", value = 0xEE } } },
+    { offset = 0x24, type = "SizeOf", target = "second" },
+    { offset = 0x28, type = "OffsetOf", target = "second.Hdr.Body" },
+    { offset = 0x2C, type = "Struct", struct = "HEADER", overrides = { Flags = { type = "U8", value = 0x2 } } },
+]"#,
+        )
+        .unwrap();
+        config.share_definitions();
+
+        let correct_buffer = vec![
+            0x2, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x41, 0x41, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x2,
+            0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x41, 0x41, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0xEE, 0x0,
+            0x0, 0x0, 0x11, 0x0, 0x0, 0x0, 0x18, 0x0, 0x0, 0x0, 0x2, 0x0, 0x0, 0x0, 0x2, 0x0, 0x0,
+            0x0, 0x41, 0x41, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        ];
+
+        let input_buffer: Vec<u8> = config.ioctls[0].build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_struct_errors() {
+        let mut ioctl = Ioctl {
+            input_buffer_size: 0x20,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x0,
+                entry_data: EntryData::Struct {
+                    struct_name: "MISSING".to_string(),
+                    overrides: HashMap::new(),
+                },
+            }]),
+            ..Default::default()
+        };
+
+        // Unknown struct template
+        assert!(ioctl.build_input_buffer().is_err());
+
+        // Override of a field that doesn't exist
+        ioctl.structs.insert(
+            "LOOP".to_string(),
+            StructTemplate {
+                size: None,
+                fields: vec![BufferContentEntry {
+                    name: Some("Inner".to_string()),
+                    offset: 0x0,
+                    entry_data: EntryData::Struct {
+                        struct_name: "LOOP".to_string(),
+                        overrides: HashMap::new(),
+                    },
+                }],
+            },
+        );
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::Struct {
+                struct_name: "LOOP".to_string(),
+                overrides: HashMap::from([("Missing".to_string(), EntryData::U8 { value: 0x1 })]),
+            },
+        }]);
+        assert!(ioctl.build_input_buffer().is_err());
+
+        // Template that contains itself
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::Struct {
+                struct_name: "LOOP".to_string(),
+                overrides: HashMap::new(),
+            },
+        }]);
+        assert!(ioctl.build_input_buffer().is_err());
+    }
+
+    #[test]
+    fn build_buffer_repeat_and_array() {
+        let config: Config = toml::from_str(
+            r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = 0x10000
+input_buffer_size = 0x30
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "SizeOf", target = "ranges", width = 2 },
+    { offset = 0x2, type = "OffsetOf", target = "ranges[1].length", width = 2 },
+    { offset = 0x4, type = "Array", values = [
+        { type = "U32", value = 0x4 },
+        { type = "U16", value = 0x8 },
+        { type = "U8", value = 0xC },
+    ] },
+    { offset = 0xC, type = "Array", stride = 2, values = [
+        { type = "U8", value = 0x1 },
+        { type = "U8", value = 0x2 },
+    ] },
+    { name = "ranges", offset = 0x10, type = "Repeat", count = 3, stride = 0x8, entries = [
+        { offset = 0x0, type = "U32", value = 0x1000 },
+        { name = "length", offset = 0x4, type = "U16", value = 0x20 },
+    ] },
+]"#,
+        )
+        .unwrap();
+
+        let correct_buffer = vec![
+            0x18, 0x0, 0x1C, 0x0, 0x4, 0x0, 0x0, 0x0, 0x8, 0x0, 0xC, 0x0, 0x1, 0x0, 0x2, 0x0, 0x0,
+            0x10, 0x0, 0x0, 0x20, 0x0, 0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x20, 0x0, 0x0, 0x0, 0x0,
+            0x10, 0x0, 0x0, 0x20, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        ];
+
+        let input_buffer: Vec<u8> = config.ioctls[0].build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_repeat_oob() {
+        let ioctl = Ioctl {
+            input_buffer_size: 0x1000,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x10,
+                entry_data: EntryData::Repeat {
+                    count: usize::MAX / 2,
+                    stride: 0x10,
+                    entries: vec![BufferContentEntry {
+                        name: None,
+                        offset: 0x0,
+                        entry_data: EntryData::U32 {
+                            value: 0x41,
+                            endian: Endian::Little,
+                        },
+                    }],
+                },
+            }]),
+            ..Default::default()
+        };
+
+        assert!(ioctl.build_input_buffer().is_err());
+    }
+
+    #[test]
+    fn build_buffer_repeat_bad_stride() {
+        let record = vec![BufferContentEntry {
+            name: None,
+            offset: 0x2,
+            entry_data: EntryData::U32 {
+                value: 0x41,
+                endian: Endian::Little,
+            },
+        }];
+        let mut ioctl = Ioctl {
+            input_buffer_size: 0x100,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x0,
+                entry_data: EntryData::Repeat {
+                    count: 0xFFFFFFFF,
+                    stride: 0,
+                    entries: record.clone(),
+                },
+            }]),
+            ..Default::default()
+        };
+
+        // Every record at the same offset
+        assert_eq!(
+            BufferErrorKind::Invalid("Repeat stride must not be 0"),
+            ioctl.build_input_buffer().unwrap_err().kind
+        );
+
+        // Records overlapping the next one
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::Repeat {
+                count: 0xFFFFFFFF,
+                stride: 0x4,
+                entries: record.clone(),
+            },
+        }]);
+        assert_eq!(
+            BufferErrorKind::Invalid("Repeat stride is smaller than its records"),
+            ioctl.build_input_buffer().unwrap_err().kind
+        );
+
+        // Records that don't overlap, but don't all fit
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::Repeat {
+                count: 0xFFFFFFFF,
+                stride: 0x6,
+                entries: record,
+            },
+        }]);
+        assert!(matches!(
+            ioctl.build_input_buffer().unwrap_err().kind,
+            BufferErrorKind::OutOfBounds {
+                offset: 0x0,
+                size: 0x5FFFFFFFA,
+                buffer_size: 0x100
+            }
+        ));
+    }
+
+    #[test]
+    fn build_buffer_flags_and_bits() {
+        let mut config: Config = toml::from_str(
+            r#"device_name = '\\.\Test'
+
+[constants]
+FLAG_A = 0x1
+FLAG_B = 0x2
+FLAG_C = 0x80000000
+
+[enums.MODE]
+READ = 0x10
+WRITE = 0x20
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = 0x10000
+input_buffer_size = 0x10
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "U32", value = 0x100 },
+    { offset = 0x0, type = "Flags", value = ["FLAG_A", "FLAG_C", "MODE.WRITE"] },
+    { offset = 0x4, type = "Flags", value = ["FLAG_B"], width = 2, endian = "big" },
+    { offset = 0x8, type = "U8", value = 0x80 },
+    { offset = 0x8, type = "Bits", bit_offset = 3, bit_width = 3, value = 0x5 },
+    { offset = 0x8, type = "Bits", bit_offset = 12, bit_width = 8, value = 0xAB },
+]"#,
+        )
+        .unwrap();
+        config.share_definitions();
+
+        let correct_buffer = vec![
+            0x21, 0x1, 0x0, 0x80, 0x0, 0x2, 0x0, 0x0, 0xA8, 0xB0, 0xA, 0x0, 0x0, 0x0, 0x0, 0x0,
+        ];
+
+        let input_buffer: Vec<u8> = config.ioctls[0].build_input_buffer().unwrap();
+
+        assert_eq!(correct_buffer, input_buffer);
+    }
+
+    #[test]
+    fn build_buffer_flags_and_bits_errors() {
+        let mut ioctl = Ioctl {
+            input_buffer_size: 0x10,
+            input_buffer_content: Some(vec![BufferContentEntry {
+                name: None,
+                offset: 0x0,
+                entry_data: EntryData::Flags {
+                    value: vec!["MISSING".to_string()],
+                    width: IntWidth(4),
+                    endian: Endian::Little,
+                },
+            }]),
+            ..Default::default()
+        };
+
+        // Unknown constant
+        assert!(ioctl.build_input_buffer().is_err());
+
+        // Value wider than bit_width
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::Bits {
+                bit_offset: 0,
+                bit_width: 2,
+                value: 0x4,
+            },
+        }]);
+        assert!(ioctl.build_input_buffer().is_err());
+
+        // Bits running past the end of the buffer
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0xF,
+            entry_data: EntryData::Bits {
+                bit_offset: 4,
+                bit_width: 8,
+                value: 0x1,
+            },
+        }]);
+        assert!(ioctl.build_input_buffer().is_err());
+
+        // A bit_offset far past the end of the buffer is caught before anything is allocated
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::Bits {
+                bit_offset: 0x7FFFFFFFFFFF,
+                bit_width: 1,
+                value: 0x1,
+            },
+        }]);
+        assert!(matches!(
+            ioctl.build_input_buffer().unwrap_err().kind,
+            BufferErrorKind::OutOfBounds {
+                offset: 0x0,
+                size: 0x100000000000,
+                buffer_size: 0x10
+            }
+        ));
+
+        // As is one where the end of the bits overflows
+        ioctl.input_buffer_content = Some(vec![BufferContentEntry {
+            name: None,
+            offset: 0x0,
+            entry_data: EntryData::Bits {
+                bit_offset: usize::MAX,
+                bit_width: 8,
+                value: 0x1,
+            },
+        }]);
+        assert_eq!(
+            BufferErrorKind::Invalid("Input buffer entry content is out of bounds"),
+            ioctl.build_input_buffer().unwrap_err().kind
+        );
+    }
+
+    #[test]
+    fn find_overlaps_reports_entries_and_range() {
+        let mut ioctl = Ioctl {
+            input_buffer_size: 0x20,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::U64 {
+                        value: 0x4141414141414141,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x4,
+                    entry_data: EntryData::U32 {
+                        value: 0x42424242,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x6,
+                    entry_data: EntryData::Bits {
+                        bit_offset: 0,
+                        bit_width: 8,
+                        value: 0x1,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x10,
+                    entry_data: EntryData::U8 { value: 0x43 },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![Overlap {
+                first: 0,
+                first_part: String::new(),
+                second: 1,
+                second_part: String::new(),
+                start: 0x4,
+                end: 0x8,
+            }],
+            ioctl.find_overlaps().unwrap()
+        );
+
+        // Overlaps are only warnings unless in strict mode, and are returned with the
+        // prepared buffers rather than printed
+        assert!(ioctl.build_input_buffer().is_ok());
+        let overlaps = ioctl.prepare_buffers().unwrap().overlaps;
+        assert_eq!(1, overlaps.len());
+        assert_eq!(
+            BufferErrorKind::Overlap(Box::new(Overlap {
+                first: 0,
+                first_part: String::new(),
+                second: 1,
+                second_part: String::new(),
+                start: 0x4,
+                end: 0x8
+            })),
+            overlaps[0].kind
+        );
+
+        ioctl.strict = true;
+        assert!(ioctl.build_input_buffer().is_err());
+    }
+
+    #[test]
+    fn find_overlaps_reports_parts_of_expanded_entries() {
+        let mut ioctl = Ioctl {
+            input_buffer_size: 0x20,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::Struct {
+                        struct_name: "HEADER".to_string(),
+                        overrides: HashMap::new(),
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x10,
+                    entry_data: EntryData::Array {
+                        values: vec![
+                            EntryData::U32 {
+                                value: 0x41414141,
+                                endian: Endian::Little,
+                            },
+                            EntryData::U32 {
+                                value: 0x42424242,
+                                endian: Endian::Little,
+                            },
+                        ],
+                        stride: Some(0x2),
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+        ioctl.structs.insert(
+            "HEADER".to_string(),
+            StructTemplate {
+                size: None,
+                fields: vec![
+                    BufferContentEntry {
+                        name: Some("Length".to_string()),
+                        offset: 0x0,
+                        entry_data: EntryData::U32 {
+                            value: 0x8,
+                            endian: Endian::Little,
+                        },
+                    },
+                    BufferContentEntry {
+                        name: None,
+                        offset: 0x2,
+                        entry_data: EntryData::U16 {
+                            value: 0x1,
+                            endian: Endian::Little,
+                        },
+                    },
+                ],
+            },
+        );
+
+        // Parts of the same entry are told apart, rather than reported as "entries 0 and 0"
+        let overlaps = ioctl.find_overlaps().unwrap();
+        assert_eq!(
+            vec![
+                Overlap {
+                    first: 0,
+                    first_part: "Length".to_string(),
+                    second: 0,
+                    second_part: "fields[1]".to_string(),
+                    start: 0x2,
+                    end: 0x4,
+                },
+                Overlap {
+                    first: 1,
+                    first_part: "[0]".to_string(),
+                    second: 1,
+                    second_part: "[1]".to_string(),
+                    start: 0x12,
+                    end: 0x14,
+                },
+            ],
+            overlaps
+        );
+        assert_eq!(
+            "entries 0 (Length) and 0 (fields[1]) overlap at 0x2..0x4",
+            overlaps[0].to_string()
+        );
+    }
+
+    #[test]
+    fn coverage_map_shows_gaps() {
+        let ioctl = Ioctl {
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x14,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::U32 {
+                        value: 0x1,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x2,
+                    entry_data: EntryData::U16 {
+                        value: 0x1,
+                        endian: Endian::Little,
+                    },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x10,
+                    entry_data: EntryData::Fill {
+                        value: 0x41,
+                        length: 0x2,
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let correct_map = "IOCTL_TEST (0x14 bytes): '#' set, 'X' overlapping, '.' unset
+0x0000  ##XX.... ........
+0x0010  ##..
+Unset gaps:
+  0x4..0x10 (0xC bytes)
+  0x12..0x14 (0x2 bytes)
+";
+
+        assert_eq!(correct_map, ioctl.coverage_map().unwrap());
+    }
+
+    #[test]
+    fn build_buffer_error_context() {
+        let ioctl = Ioctl {
+            name: "IOCTL_TEST".to_string(),
+            input_buffer_size: 0x10,
+            input_buffer_content: Some(vec![
+                BufferContentEntry {
+                    name: None,
+                    offset: 0x0,
+                    entry_data: EntryData::U8 { value: 0x41 },
+                },
+                BufferContentEntry {
+                    name: None,
+                    offset: 0xC,
+                    entry_data: EntryData::U64 {
+                        value: 0x4141414141414141,
+                        endian: Endian::Little,
+                    },
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let error = ioctl.build_input_buffer().unwrap_err();
+
+        assert_eq!(
+            BufferError {
+                ioctl: "IOCTL_TEST".to_string(),
+                aux_buffer: None,
+                entry: 1,
+                file: None,
+                line: None,
+                kind: BufferErrorKind::OutOfBounds {
+                    offset: 0xC,
+                    size: 0x8,
+                    buffer_size: 0x10,
+                },
+            },
+            error
+        );
+    }
+
+    #[test]
+    fn config_records_entry_lines() {
+        let config_path = std::env::temp_dir().join("ioctiller_entry_lines.toml");
+        fs::write(
+            &config_path,
+            r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_1"
+code = 0x1
+input_buffer_size = 0x10
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "U32", value = 1 },
+]
+
+[[ioctls]]
+name = "IOCTL_2"
+code = 0x2
+input_buffer_size = 0x10
+output_buffer_size = 0x0
+
+[[ioctls.input_buffer_content]]
+offset = 0x0
+type = "U8"
+value = 0x41
+
+[[ioctls.input_buffer_content]]
+offset = 0x10
+type = "U8"
+value = 0x41
+"#,
+        )
+        .unwrap();
+
+        let cli = Cli {
+            file_path: config_path.clone(),
+            validate: false,
+            format: None,
+        };
+        let config = Config::build(&cli).unwrap();
+        fs::remove_file(&config_path).unwrap();
+
+        assert_eq!(vec![9], config.ioctls[0].entry_lines);
+
+        let error = config.ioctls[1].build_input_buffer().unwrap_err();
+        assert_eq!(1, error.entry);
+        assert_eq!(Some(23), error.line);
+    }
+
+    fn build_config(name: &str, contents: &str) -> Result<Config, Box<dyn Error>> {
+        let config_path = std::env::temp_dir().join(name);
+        fs::write(&config_path, contents).unwrap();
+
+        let cli = Cli {
+            file_path: config_path.clone(),
+            validate: false,
+            format: None,
+        };
+        let config = Config::build(&cli);
+        fs::remove_file(&config_path).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn config_evaluates_expressions() {
+        let config = build_config(
+            "ioctiller_vars.toml",
+            r#"device_name = '\\.\Test'
+
+[vars]
+count = 2
+HDR_SIZE = "vars.count * 8"
+PAGE = "1 << 12"
+
+[constants]
+FLAG_BIG = "PAGE | 1"
+
+[[ioctls]]
+name = "IOCTL_1"
+code = "0x222000 | 3 << 2"
+input_buffer_size = "HDR_SIZE + 0x10"
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = "HDR_SIZE", type = "U32", value = "PAGE + 1" },
+    { offset = 0x0, type = "Fill", value = 0x41, length = "HDR_SIZE - 8" },
+]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(16, config.vars["HDR_SIZE"]);
+        assert_eq!(0x1001, config.constants["FLAG_BIG"]);
+
+        let ioctl = &config.ioctls[0];
+        assert_eq!(0x22200C, ioctl.code);
+
+        let mut expected = vec![0x41; 8];
+        expected.resize(0x10, 0);
+        expected.extend_from_slice(&0x1001u32.to_le_bytes());
+        expected.resize(0x20, 0);
+        assert_eq!(expected, ioctl.build_input_buffer().unwrap());
+    }
+
+    #[test]
+    fn config_expression_errors() {
+        let config = |value: &str| {
+            build_config(
+                "ioctiller_vars_errors.toml",
+                &format!(
+                    r#"device_name = '\\.\Test'
+
+[vars]
+BIG = 0x1000
+
+[[ioctls]]
+name = "IOCTL_1"
+code = 0x1
+input_buffer_size = 0x10
+output_buffer_size = 0x0
+input_buffer_content = [
+    {{ offset = 0x0, type = "U8", value = {value} }},
+]
+"#
+                ),
+            )
+        };
+
+        let error = config(r#""MISSING + 1""#).unwrap_err().to_string();
+        assert!(error.contains("undefined name `MISSING`"), "{error}");
+
+        let error = config(r#""BIG""#).unwrap_err().to_string();
+        assert!(error.contains("4096 is out of range"), "{error}");
+
+        let error = config(r#""1 << 200""#).unwrap_err().to_string();
+        assert!(error.contains("overflow"), "{error}");
+    }
+
+    #[test]
+    fn config_builds_ctl_codes() {
+        let config = build_config(
+            "ioctiller_ctl_code.toml",
+            r#"device_name = '\\.\Test'
+
+[vars]
+FILE_DEVICE_UNKNOWN = 0x22
+
+[[ioctls]]
+name = "IOCTL_1"
+code = { device_type = "FILE_DEVICE_UNKNOWN", function = 0x801, method = "neither", access = "read" }
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "IOCTL_2"
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[ioctls.code]
+device_type = 0x22
+function = 0x802
+
+[[ioctls]]
+name = "TIOCGPTN"
+device_name = "/dev/ptmx"
+code = { dir = "read", type = "'T'", nr = 0x30, size = 4 }
+input_buffer_size = 0x0
+output_buffer_size = 0x4
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(0x226007, config.ioctls[0].code);
+        assert_eq!(0x222008, config.ioctls[1].code);
+        assert_eq!(0x80045430, config.ioctls[2].code);
+        assert_eq!(
+            "IOCTL_1:0x226007 (device_type=0x22 function=0x801 METHOD_NEITHER FILE_READ_ACCESS)",
+            config.ioctls[0].to_string()
+        );
+        assert_eq!(
+            "TIOCGPTN:0x80045430 (_IOR type='T' nr=0x30 size=0x4)",
+            config.ioctls[2].to_string()
+        );
+
+        let error = build_config(
+            "ioctiller_ctl_code_error.toml",
+            r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_1"
+code = { device_type = 0x22, function = 0x1000 }
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("12 bits"), "{error}");
+
+        let error = build_config(
+            "ioctiller_ioc_code_error.toml",
+            r#"device_name = '/dev/ptmx'
+
+[[ioctls]]
+name = "IOCTL_1"
+code = { dir = "read", type = "'T'", nr = 0x30, function = 0x1 }
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("along with dir"), "{error}");
+    }
+
+    #[test]
+    fn config_resolves_devices() {
+        let config = build_config(
+            "ioctiller_devices.toml",
+            r#"device_name = '\\.\Default'
+
+[[devices]]
+name = "control"
+path = '\\.\Control'
+aliases = ["ctl"]
+
+[[devices]]
+name = "raw"
+path = '\\.\GLOBALROOT\Device\Raw'
+
+[[ioctls]]
+name = "IOCTL_1"
+code = 0x1
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "IOCTL_2"
+code = 0x2
+device = "ctl"
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "IOCTL_3"
+code = 0x3
+device = "raw"
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "IOCTL_4"
+code = 0x4
+device_name = '\\.\Other'
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                r"\\.\Default",
+                r"\\.\Control",
+                r"\\.\GLOBALROOT\Device\Raw",
+                r"\\.\Other"
+            ],
+            config.device_names()
+        );
+
+        let config = |devices: &str| {
+            build_config(
+                "ioctiller_devices_errors.toml",
+                &format!(
+                    r#"[[devices]]
+name = "control"
+path = '\\.\Control'
+
+{devices}
+
+[[ioctls]]
+name = "IOCTL_1"
+code = 0x1
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+"#
+                ),
+            )
+        };
+
+        let error = config("").unwrap_err().to_string();
+        assert!(error.contains("no device"), "{error}");
+
+        let error = config("[[devices]]\nname = \"other\"\npath = 'x'\naliases = [\"control\"]")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("more than once"), "{error}");
+    }
+
+    #[test]
+    fn config_resolves_open_options() {
+        let config = build_config(
+            "ioctiller_open_options.toml",
+            r#"[[devices]]
+name = "control"
+path = '\\.\Control'
+desired_access = "GENERIC_READ | SYNCHRONIZE"
+share_mode = "FILE_SHARE_READ | FILE_SHARE_WRITE"
+
+[[ioctls]]
+name = "IOCTL_1"
+code = 0x1
+device = "control"
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "IOCTL_2"
+code = 0x2
+device = "control"
+desired_access = 0
+flags_and_attributes = "FILE_ATTRIBUTE_NORMAL | FILE_FLAG_BACKUP_SEMANTICS"
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+"#,
+        )
+        .unwrap();
+
+        let options = config.ioctls[0].open_options();
+        assert_eq!(Some(0x8010_0000), options.desired_access);
+        assert_eq!(Some(0x3), options.share_mode);
+        assert_eq!(None, options.flags_and_attributes);
+
+        let options = config.ioctls[1].open_options();
+        assert_eq!(Some(0), options.desired_access);
+        assert_eq!(Some(0x3), options.share_mode);
+        assert_eq!(Some(0x0200_0080), options.flags_and_attributes);
+
+        assert_eq!(
+            open_options::DEFAULT_ACCESS_MATRIX.len() + 1,
+            config.ioctls[0].access_matrix().len()
+        );
+        assert_eq!(
+            open_options::DEFAULT_ACCESS_MATRIX.to_vec(),
+            config.ioctls[1].access_matrix()
+        );
+    }
+
+    #[test]
+    fn config_layers_includes() {
+        let config_dir = std::env::temp_dir().join("ioctiller_includes");
+        let _ = fs::remove_dir_all(&config_dir);
+        fs::create_dir_all(config_dir.join("structs")).unwrap();
+
+        fs::write(
+            config_dir.join("common.toml"),
+            r#"device_name = '\\.\Common'
+
+[vars]
+HDR_SIZE = 0x8
+
+[constants]
+FLAG_A = 0x1
+FLAG_B = 0x2
+
+[[ioctls]]
+name = "IOCTL_COMMON"
+code = 0x1
+input_buffer_size = 0x4
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "U32", value = 1 },
+    { offset = 0x4, type = "U8", value = 1 },
+]
+"#,
+        )
+        .unwrap();
+        fs::write(
+            config_dir.join("structs").join("a.toml"),
+            "[structs.HEADER]\nsize = \"HDR_SIZE\"\nfields = []\n",
+        )
+        .unwrap();
+        fs::write(
+            config_dir.join("structs").join("b.toml"),
+            "[structs.HEADER]\nsize = \"HDR_SIZE * 2\"\nfields = []\n",
+        )
+        .unwrap();
+        fs::write(
+            config_dir.join("root.toml"),
+            r#"include = ["common.toml", "structs/*.toml"]
+
+[vars]
+HDR_SIZE = 0x10
+
+[constants]
+FLAG_B = 0x4
+
+[[ioctls]]
+name = "IOCTL_ROOT"
+code = 0x2
+input_buffer_size = "HDR_SIZE"
+output_buffer_size = 0x0
+"#,
+        )
+        .unwrap();
+
+        let build = |file_name: &str| {
+            Config::build(&Cli {
+                file_path: config_dir.join(file_name),
+                validate: false,
+                format: None,
+            })
+        };
+
+        let config = build("root.toml").unwrap();
+        assert_eq!(r"\\.\Common", config.device_name);
+        assert_eq!(0x1, config.constants["FLAG_A"]);
+        assert_eq!(0x4, config.constants["FLAG_B"]);
+        assert_eq!(Some(0x20), config.structs["HEADER"].size);
+        assert_eq!(
+            vec!["IOCTL_COMMON", "IOCTL_ROOT"],
+            config
+                .ioctls
+                .iter()
+                .map(|ioctl| ioctl.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0x10, config.ioctls[1].input_buffer_size);
+
+        let error = config.ioctls[0].build_input_buffer().unwrap_err();
+        assert_eq!(
+            Some(config_dir.join("common.toml").into_boxed_path()),
+            error.file
+        );
+        assert!(error.to_string().contains("common.toml line 17"), "{error}");
+
+        fs::write(
+            config_dir.join("duplicate.toml"),
+            "include = [\"common.toml\"]\n\n[[ioctls]]\nname = \"IOCTL_COMMON\"\ncode = 0x1\ninput_buffer_size = 0x0\noutput_buffer_size = 0x0\n",
+        )
+        .unwrap();
+        let error = build("duplicate.toml").unwrap_err().to_string();
+        assert!(
+            error.contains("IOCTL_COMMON is defined in both")
+                && error.contains("common.toml line 10")
+                && error.contains("duplicate.toml line 3"),
+            "{error}"
+        );
+
+        fs::write(
+            config_dir.join("twice.toml"),
+            "[[ioctls]]\nname = \"IOCTL_TWICE\"\ncode = 0x1\ninput_buffer_size = 0x0\noutput_buffer_size = 0x0\n\n[[ioctls]]\nname = \"IOCTL_TWICE\"\ncode = 0x2\ninput_buffer_size = 0x0\noutput_buffer_size = 0x0\n",
+        )
+        .unwrap();
+        let error = build("twice.toml").unwrap_err().to_string();
+        assert!(
+            error.starts_with("IOCTL_TWICE is defined twice in")
+                && error.ends_with("twice.toml (lines 1 and 7)"),
+            "{error}"
+        );
+
+        fs::write(
+            config_dir.join("cycle.toml"),
+            "include = [\"cycle.toml\"]\n",
+        )
+        .unwrap();
+        let error = build("cycle.toml").unwrap_err().to_string();
+        assert!(error.contains("includes itself"), "{error}");
+
+        fs::write(
+            config_dir.join("missing.toml"),
+            "include = [\"nope/*.toml\"]\n",
+        )
+        .unwrap();
+        let error = build("missing.toml").unwrap_err().to_string();
+        assert!(error.contains("matched no files"), "{error}");
+
+        fs::remove_dir_all(&config_dir).unwrap();
+    }
+
+    #[test]
+    fn config_formats_build_identical_buffers() {
+        let toml_contents = r#"device_name = '\\.\Test'
+
+[vars]
+HDR_SIZE = 0x8
+
+[constants]
+FLAG_A = 0x1
+
+[enums.MODE]
+WRITE = 0x20
+
+[structs.HEADER]
+size = "HDR_SIZE"
+fields = [
+    { name = "Size", offset = 0x0, type = "SizeOf", target = "Body" },
+    { name = "Body", offset = 0x4, type = "U16", value = 0x4141, endian = "big" },
+]
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = { device_type = 0x22, function = 0x800, method = "neither" }
+input_buffer_size = "HDR_SIZE * 8"
+output_buffer_size = 0x0
+input_buffer_content = [
+    { name = "hdr", offset = 0x0, type = "Struct", struct = "HEADER", overrides = { Body = { type = "U8", value = 0x42 } } },
+    { offset = 0x8, type = "Flags", value = ["FLAG_A", "MODE.WRITE"] },
+    { offset = 0xC, type = "I32", value = -2 },
+    { offset = 0x10, type = "String16", value = "AB", null_terminate = true },
+    { offset = 0x18, type = "Bytes", value = "deadbeef" },
+    { offset = 0x1C, type = "Fill", value = 0x41, length = "HDR_SIZE - 4" },
+    { offset = 0x20, type = "Array", values = [{ type = "U8", value = 1 }, { type = "U8", value = 2 }], stride = 4 },
+    { offset = 0x28, type = "Repeat", count = 2, stride = 4, entries = [{ offset = 0x0, type = "U16", value = 0x7 }] },
+    { offset = 0x30, type = "F32", value = 1.5 },
+    { offset = 0x38, type = "SizeOf", target = "hdr", width = 8 },
+]
+"#;
+
+        let value: toml::Value = toml::from_str(toml_contents).unwrap();
+        let json_contents = serde_json::to_string_pretty(&value).unwrap();
+        let yaml_contents = serde_yaml::to_string(&value).unwrap();
+
+        let toml_config = build_config("ioctiller_formats.toml", toml_contents).unwrap();
+        let json_config = build_config("ioctiller_formats.json", &json_contents).unwrap();
+        let yaml_config = build_config("ioctiller_formats.yaml", &yaml_contents).unwrap();
+
+        let toml_ioctl = &toml_config.ioctls[0];
+        let input_buffer = toml_ioctl.build_input_buffer().unwrap();
+        assert_eq!(0x40, input_buffer.len());
+        assert_eq!(&[0x42, 0x0], &input_buffer[0x4..0x6]);
+        assert_eq!(Some(19), toml_ioctl.line);
+
+        for config in [&json_config, &yaml_config] {
+            let ioctl = &config.ioctls[0];
+            assert_eq!(toml_config.device_name, config.device_name);
+            assert_eq!(toml_ioctl.code, ioctl.code);
+            assert_eq!(None, ioctl.line);
+            assert_eq!(input_buffer, ioctl.build_input_buffer().unwrap());
+        }
+
+        // Hand-written configs, as the other formats are more likely to be
+        let json_config = build_config(
+            "ioctiller_handwritten.json",
+            r#"{
+    "device_name": "\\\\.\\Test",
+    "vars": { "HDR_SIZE": "0x10" },
+    "ioctls": [
+        {
+            "name": "IOCTL_JSON",
+            "code": "0x222000",
+            "input_buffer_size": "HDR_SIZE",
+            "output_buffer_size": 0,
+            "input_buffer_content": [
+                { "offset": 0, "type": "U32", "value": "0xDEADBEEF" },
+                { "offset": "HDR_SIZE - 4", "type": "String8", "value": "AB" }
+            ]
+        }
+    ]
+}"#,
+        )
+        .unwrap();
+        let yaml_config = build_config(
+            "ioctiller_handwritten.yml",
+            r#"device_name: '\\.\Test'
+vars:
+  HDR_SIZE: "0x10"
+ioctls:
+  - name: IOCTL_YAML
+    code: 0x222000
+    input_buffer_size: HDR_SIZE
+    output_buffer_size: 0
+    input_buffer_content:
+      - { offset: 0, type: U32, value: 0xDEADBEEF }
+      - { offset: "HDR_SIZE - 4", type: String8, value: AB }
+"#,
+        )
+        .unwrap();
+
+        let mut expected = 0xDEADBEEFu32.to_le_bytes().to_vec();
+        expected.resize(0xC, 0);
+        expected.extend_from_slice(b"AB");
+        expected.resize(0x10, 0);
+
+        for config in [&json_config, &yaml_config] {
+            assert_eq!(r"\\.\Test", config.device_name);
+            assert_eq!(0x222000, config.ioctls[0].code);
+            assert_eq!(expected, config.ioctls[0].build_input_buffer().unwrap());
+        }
+    }
+
+    #[test]
+    fn config_format_flag_overrides_extension() {
+        let config_path = std::env::temp_dir().join("ioctiller_format_flag.cfg");
+        fs::write(
+            &config_path,
+            r#"{ "device_name": "\\\\.\\Test", "ioctls": [] }"#,
+        )
+        .unwrap();
+
+        let build = |format| {
+            Config::build(&Cli {
+                file_path: config_path.clone(),
+                validate: false,
+                format,
+            })
+        };
+
+        assert!(build(None).is_err());
+        assert_eq!(
+            r"\\.\Test",
+            build(Some(ConfigFormat::Json)).unwrap().device_name
+        );
+
+        fs::remove_file(&config_path).unwrap();
+    }
+
+    #[test]
+    fn allocate_buffers_with_declared_sizes() {
+        let mut ioctl: Ioctl = toml::from_str(
+            r#"
+            name = "IOCTL_TEST"
+            code = 0x222000
+            input_buffer_size = 0x8
+            output_buffer_size = 0x10
+            declared_input_buffer_size = 0x1000
+            allocated_input_buffer_size = 0x4
+            allocated_output_buffer_size = 0x2
+            input_buffer_content = [
+                { offset = 0x0, type = "U64", value = 0x4142434445464748 },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(0x1000, ioctl.declared_input_buffer_size());
+        assert_eq!(0x10, ioctl.declared_output_buffer_size());
+        let buffers = ioctl.prepare_buffers().unwrap();
+        assert_eq!(
+            Some(&[0x48, 0x47, 0x46, 0x45][..]),
+            buffers.input.as_deref()
+        );
+        assert_eq!(Some(&[0, 0][..]), buffers.output.as_deref());
+
+        ioctl.allocated_input_buffer_size = Some(0xA);
+        ioctl.guard_page = GuardPage::End;
+        assert_eq!(
+            Some(&[0x48, 0x47, 0x46, 0x45, 0x44, 0x43, 0x42, 0x41, 0, 0][..]),
+            ioctl.prepare_buffers().unwrap().input.as_deref()
+        );
+
+        ioctl.null_input = true;
+        ioctl.null_output = true;
+        let buffers = ioctl.prepare_buffers().unwrap();
+        assert!(buffers.input.is_none());
+        assert!(buffers.output.is_none());
+        assert_eq!(0x1000, ioctl.declared_input_buffer_size());
+    }
+
+    #[test]
+    fn fuzzed_input_buffer_size_follows_the_input() {
+        let mut ioctl = Ioctl {
+            input_buffer_size: 0x20,
+            ..Default::default()
+        };
+
+        // Shorter mutated inputs are declared at their own length, so that reading them
+        // doesn't run into a guard page
+        assert_eq!(0x7, ioctl.fuzzed_input_buffer_size(0x7));
+
+        // Unless the config declares a size that differs from the allocation on purpose
+        ioctl.declared_input_buffer_size = Some(0x1000);
+        assert_eq!(0x1000, ioctl.fuzzed_input_buffer_size(0x7));
+
+        ioctl.declared_input_buffer_size = None;
+        ioctl.allocated_input_buffer_size = Some(0x10);
+        assert_eq!(0x20, ioctl.fuzzed_input_buffer_size(0x7));
+    }
+
+    #[test]
+    fn prepare_buffers_with_pointers() {
+        let ioctl: Ioctl = toml::from_str(
+            r#"
+            name = "IOCTL_TEST"
+            code = 0x222003
+            input_buffer_size = 0x10
+            output_buffer_size = 0x0
+            input_buffer_content = [
+                { offset = 0x0, type = "Pointer", target = "NAME", add = 2, width = 8 },
+                { offset = 0x8, type = "U32", value = 0x8 },
+            ]
+
+            [aux_buffers.NAME]
+            size = 0x10
+            content = [
+                { offset = 0x0, type = "Pointer", target = "NEXT", width = 8 },
+                { offset = 0x8, type = "String16", value = "Test" },
+            ]
+
+            [aux_buffers.NEXT]
+            size = 0x4
+            content = [{ offset = 0x0, type = "U32", value = 0x41414141 }]
+            "#,
+        )
+        .unwrap();
+
+        let buffers = ioctl.prepare_buffers().unwrap();
+        let input = buffers.input.as_deref().unwrap();
+        let name = &buffers.aux["NAME"];
+        let next = &buffers.aux["NEXT"];
+
+        assert_eq!((name.as_ptr() as u64 + 2).to_le_bytes(), input[0x0..0x8]);
+        assert_eq!((next.as_ptr() as u64).to_le_bytes(), name[0x0..0x8]);
+        assert_eq!(
+            [0x54, 0x0, 0x65, 0x0, 0x73, 0x0, 0x74, 0x0],
+            name[0x8..0x10]
+        );
+        assert_eq!([0x41, 0x41, 0x41, 0x41], next[..]);
+
+        // Without allocating, pointers are written as if the buffers were at address 0
+        assert_eq!(
+            vec![0x2, 0, 0, 0, 0, 0, 0, 0, 0x8, 0, 0, 0, 0, 0, 0, 0],
+            ioctl.build_input_buffer().unwrap()
+        );
+    }
+
+    #[test]
+    fn prepare_buffers_with_hostile_pointers() {
+        let ioctl: Ioctl = toml::from_str(
+            r#"
+            name = "IOCTL_TEST"
+            code = 0x222003
+            input_buffer_size = 0x14
+            output_buffer_size = 0x0
+            input_buffer_content = [
+                { offset = 0x0, type = "HostilePointer", value = "kernel", width = 8 },
+                { offset = 0x8, type = "HostilePointer", value = "read_only", add = 4, width = 8 },
+                { offset = 0x10, type = "HostilePointer", value = "kernel", width = 4 },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let buffers = ioctl.prepare_buffers().unwrap();
+        let input = buffers.input.as_deref().unwrap();
+        let read_only = HostilePointer::ReadOnly.address(Some(&buffers.hostile), 8);
+
+        assert_eq!(0xFFFF800000000000u64.to_le_bytes(), input[0x0..0x8]);
+        assert_eq!((read_only + 4).to_le_bytes(), input[0x8..0x10]);
+        assert_eq!(0x80000000u32.to_le_bytes(), input[0x10..0x14]);
+
+        assert!(
+            toml::from_str::<BufferContentEntry>(
+                "offset = 0\ntype = \"HostilePointer\"\nvalue = \"nonsense\""
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn pointer_entry_errors() {
+        let mut ioctl: Ioctl = toml::from_str(
+            r#"
+            name = "IOCTL_TEST"
+            code = 0x222003
+            input_buffer_size = 0x8
+            output_buffer_size = 0x0
+            input_buffer_content = [
+                { offset = 0x0, type = "Pointer", target = "MISSING" },
+            ]
+
+            [aux_buffers.NAME]
+            size = 0x4
+            content = [{ offset = 0x2, type = "U32", value = 0x41414141 }]
+            "#,
+        )
+        .unwrap();
 
         assert!(ioctl.build_input_buffer().is_err());
+
+        ioctl.input_buffer_content = None;
+        let Err(DispatchError::Buffer(error)) = ioctl.prepare_buffers() else {
+            panic!("Aux buffer entry should be out of bounds");
+        };
+        assert_eq!(Some("NAME".to_string()), error.aux_buffer);
+        assert_eq!(0, error.entry);
+
+        assert!(
+            toml::from_str::<BufferContentEntry>(
+                "offset = 0\ntype = \"Pointer\"\ntarget = \"NAME\"\nwidth = 2"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn entry_data_deserialize_endian() {
+        let entry: BufferContentEntry = toml::from_str(
+            r#"offset = 0x4
+type = "U32"
+value = 0x1337
+endian = "big""#,
+        )
+        .unwrap();
+
+        assert_eq!(0x4, entry.offset);
+        assert_eq!(
+            vec![0x0, 0x0, 0x13, 0x37],
+            entry.entry_data.to_bytes().unwrap()
+        );
+
+        let entry: BufferContentEntry =
+            toml::from_str("offset = 0x0\ntype = \"I16\"\nvalue = -3").unwrap();
+
+        assert_eq!(vec![0xFD, 0xFF], entry.entry_data.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn entry_data_deserialize_invalid() {
+        // Out of range for the declared type
+        assert!(
+            toml::from_str::<BufferContentEntry>("offset = 0\ntype = \"I8\"\nvalue = 200").is_err()
+        );
+        assert!(
+            toml::from_str::<BufferContentEntry>("offset = 0\ntype = \"U16\"\nvalue = -1").is_err()
+        );
+
+        // Unknown byte order
+        assert!(
+            toml::from_str::<BufferContentEntry>(
+                "offset = 0\ntype = \"U32\"\nvalue = 1\nendian = \"middle\""
+            )
+            .is_err()
+        );
     }
 
     #[test]
@@ -0,0 +1,90 @@
+use crate::open_options::{self, OpenOptions};
+use crate::transport::{DeviceTransport, IoctlStatus};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+/// Transport that sends ioctls to Linux device nodes, e.g. `/dev/ptmx`, with `ioctl(2)`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinuxTransport;
+
+impl DeviceTransport for LinuxTransport {
+    type Handle = File;
+
+    /// Opens the device node for reading and/or writing, depending on whether
+    /// `desired_access` grants read or write access. A mask that grants neither opens it for
+    /// reading, as Linux has no way of opening a file with no access that ioctls can be sent
+    /// through. Overlapped IOCTLs open the node non-blocking. The other `CreateFileW` options
+    /// have no equivalent, so are ignored.
+    fn open(&self, device_name: &str, options: &OpenOptions, overlapped: bool) -> io::Result<File> {
+        let desired_access = options.desired_access_or_default();
+        let write = open_options::grants_write(desired_access);
+        let read = open_options::grants_read(desired_access) || !write;
+
+        // Opening a tty shouldn't make it our controlling terminal
+        let mut flags = libc::O_NOCTTY;
+        if overlapped {
+            flags |= libc::O_NONBLOCK;
+        }
+
+        std::fs::OpenOptions::new()
+            .read(read)
+            .write(write)
+            .custom_flags(flags)
+            .open(device_name)
+    }
+
+    /// Linux ioctls take a single argument rather than separate input and output buffers,
+    /// and the kernel gets the size of the argument from the request number, so the sizes
+    /// are ignored. If there is an output buffer, the input is copied into the start of it
+    /// and the output buffer is passed, so that the driver can both read and write it.
+    /// Otherwise the input buffer is passed, or NULL if there is neither.
+    ///
+    /// Linux doesn't report how much of the argument the driver wrote, so a successful ioctl
+    /// returns the whole output buffer.
+    fn ioctl(
+        &self,
+        handle: &File,
+        code: u32,
+        input_buffer: Option<&[u8]>,
+        _input_buffer_size: usize,
+        output_buffer: Option<&mut [u8]>,
+        _output_buffer_size: usize,
+    ) -> io::Result<IoctlStatus> {
+        let (argument, output_len) = match (output_buffer, input_buffer) {
+            (Some(output_buffer), input_buffer) => {
+                if let Some(input_buffer) = input_buffer {
+                    let len = input_buffer.len().min(output_buffer.len());
+                    output_buffer[..len].copy_from_slice(&input_buffer[..len]);
+                }
+
+                (output_buffer.as_mut_ptr(), output_buffer.len())
+            }
+            (None, Some(input_buffer)) => (input_buffer.as_ptr() as *mut u8, 0),
+            (None, None) => (std::ptr::null_mut(), 0),
+        };
+
+        let result = unsafe { libc::ioctl(handle.as_raw_fd(), code as _, argument) };
+
+        if result == -1 {
+            let error = io::Error::last_os_error();
+
+            return Ok(IoctlStatus {
+                bytes_returned: 0,
+                status: error.raw_os_error().unwrap_or(libc::EIO),
+            });
+        }
+
+        Ok(IoctlStatus {
+            bytes_returned: output_len,
+            status: 0,
+        })
+    }
+
+    fn close(&self, handle: File) -> io::Result<()> {
+        drop(handle);
+
+        Ok(())
+    }
+}
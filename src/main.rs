@@ -1,8 +1,12 @@
 use inquire::{MultiSelect, Select, list_option::ListOption, prompt_u32, validator::Validation};
-use ioctiller::dispatch::{FuzzIoctlDispatcher, SingleIoctlDispatcher};
+use ioctiller::dispatch::{AccessMatrixDispatcher, FuzzIoctlDispatcher, SingleIoctlDispatcher};
+use ioctiller::report::{AccessMatrixReporter, FindingsReporter, PrintReporter, Reporter};
+use ioctiller::transport::NativeTransport;
+use ioctiller::validate::Severity;
 use ioctiller::{Cli, Config, Ioctl};
 use std::env;
 use std::process;
+use std::sync::Arc;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -19,38 +23,58 @@ fn main() {
         process::exit(1);
     });
 
+    if cli.validate {
+        validate(&config);
+        return;
+    }
+
     // Prompt user for mode
-    let mode_options: Vec<&str> = vec!["Send single", "Fuzz single", "Fuzz multiple"];
+    let mode_options: Vec<&str> = vec![
+        "Send single",
+        "Fuzz single",
+        "Fuzz multiple",
+        "Show coverage map",
+        "Replay across access masks",
+    ];
     let mode: &str = Select::new("What would you like to do?", mode_options)
         .prompt()
         .expect("Error selecting mode");
 
     match mode {
         "Send single" => {
-            // Inquire's Select option requires that the option vec is moved. Therefore we clone it,
-            // and then the selected IOCTL is just returned from the clone
-            let config_clone = config.clone();
-            let selected_ioctl: Ioctl =
-                Select::new("Please select the IOCTL to send", config_clone.ioctls)
-                    .prompt()
-                    .expect("Error selecting IOCTL");
+            let selected_ioctl = select_ioctl("Please select the IOCTL to send", &config);
 
             let ioctl_dispatcher = SingleIoctlDispatcher {
-                device_name: config.device_name,
                 ioctl: &selected_ioctl,
+                transport: NativeTransport,
             };
 
-            if let Err(e) = ioctiller::send_single(&ioctl_dispatcher) {
+            println!(
+                "Sending {} to {}",
+                selected_ioctl.name(),
+                selected_ioctl.device_name()
+            );
+
+            let outcomes = ioctiller::send_single(&ioctl_dispatcher).unwrap_or_else(|e| {
                 eprintln!("Error running ioctiller: {e}");
                 process::exit(1);
+            });
+
+            // A guard page fault is a finding rather than an error in running the tool
+            let failed = outcomes
+                .iter()
+                .any(|outcome| outcome.failed() && !outcome.guard_page_fault);
+
+            for outcome in outcomes {
+                PrintReporter.report(outcome);
+            }
+
+            if failed {
+                process::exit(1);
             }
         }
         "Fuzz single" => {
-            let config_clone = config.clone();
-            let selected_ioctl: Ioctl =
-                Select::new("Please select the IOCTL to fuzz", config_clone.ioctls)
-                    .prompt()
-                    .expect("Error selecting IOCTL");
+            let selected_ioctl = select_ioctl("Please select the IOCTL to fuzz", &config);
 
             let num_threads = prompt_u32("Number of threads:").expect("Invalid number of threads");
 
@@ -60,11 +84,19 @@ fn main() {
             }
 
             let ioctl_dispatcher = FuzzIoctlDispatcher {
-                device_name: config_clone.device_name,
                 ioctl: selected_ioctl,
+                transport: NativeTransport,
             };
 
-            if let Err(e) = ioctiller::fuzz_single(ioctl_dispatcher, num_threads) {
+            println!(
+                "Fuzzing {} on {} with {num_threads} threads",
+                ioctl_dispatcher.ioctl.name(),
+                ioctl_dispatcher.ioctl.device_name()
+            );
+
+            if let Err(e) =
+                ioctiller::fuzz_single(ioctl_dispatcher, num_threads, Arc::new(FindingsReporter))
+            {
                 eprintln!("Error running ioctiller: {e}");
                 process::exit(1);
             }
@@ -94,13 +126,17 @@ fn main() {
                     let mut dispatchers: Vec<FuzzIoctlDispatcher> = vec![];
 
                     for ioctl in ioctls {
+                        println!("Fuzzing {} on {}", ioctl.name(), ioctl.device_name());
+
                         dispatchers.push(FuzzIoctlDispatcher {
-                            device_name: config_clone.device_name.clone(),
-                            ioctl: ioctl,
+                            ioctl,
+                            transport: NativeTransport,
                         })
                     }
 
-                    if let Err(e) = ioctiller::fuzz_multiple(dispatchers) {
+                    if let Err(e) =
+                        ioctiller::fuzz_multiple(dispatchers, Arc::new(FindingsReporter))
+                    {
                         eprintln!("Error running fuzz multiple: {e}");
                         process::exit(1);
                     }
@@ -111,9 +147,97 @@ fn main() {
                 }
             }
         }
+        "Show coverage map" => {
+            let selected_ioctl = select_ioctl("Please select the IOCTL to map", &config);
+
+            match selected_ioctl.coverage_map() {
+                Ok(coverage_map) => print!("{coverage_map}"),
+                Err(e) => {
+                    eprintln!("Error building coverage map: {e}");
+                    process::exit(1);
+                }
+            }
+        }
+        "Replay across access masks" => {
+            let selected_ioctl = select_ioctl("Please select the IOCTL to replay", &config);
+
+            let ioctl_dispatcher = AccessMatrixDispatcher {
+                ioctl: &selected_ioctl,
+                access_masks: selected_ioctl.access_matrix(),
+                transport: NativeTransport,
+            };
+
+            println!(
+                "Replaying {} ({}) on {} with {} access masks",
+                selected_ioctl.name(),
+                selected_ioctl.ctl_code().access,
+                selected_ioctl.device_name(),
+                ioctl_dispatcher.access_masks.len()
+            );
+
+            let outcomes = ioctiller::send_single(&ioctl_dispatcher).unwrap_or_else(|e| {
+                eprintln!("Error running ioctiller: {e}");
+                process::exit(1);
+            });
+
+            for outcome in outcomes {
+                AccessMatrixReporter.report(outcome);
+            }
+        }
         _ => {
             eprintln!("Did not recognise mode option: {mode}");
             process::exit(1);
         }
     }
 }
+
+/// Prompts for a single IOCTL. If the config sends IOCTLs to more than one device, the device
+/// is prompted for first, and only its IOCTLs are offered.
+fn select_ioctl(message: &str, config: &Config) -> Ioctl {
+    let device_names = config.device_names();
+
+    // Inquire's Select option requires that the option vec is moved. Therefore we clone the
+    // IOCTLs, and then the selected IOCTL is just returned from the clone
+    let ioctls: Vec<Ioctl> = if device_names.len() > 1 {
+        let device_name = Select::new("Please select the device", device_names)
+            .prompt()
+            .expect("Error selecting device");
+
+        config
+            .ioctls
+            .iter()
+            .filter(|ioctl| ioctl.device_name() == device_name)
+            .cloned()
+            .collect()
+    } else {
+        config.ioctls.clone()
+    };
+
+    Select::new(message, ioctls)
+        .prompt()
+        .expect("Error selecting IOCTL")
+}
+
+/// Prints every problem found in the config, and exits with an error if any are errors
+fn validate(config: &Config) {
+    let diagnostics = config.validate();
+
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+
+    println!(
+        "Checked {} IOCTLs: {errors} errors, {warnings} warnings",
+        config.ioctls.len()
+    );
+
+    if errors > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,429 @@
+use crate::expr;
+use crate::open_options::OpenOptions;
+use crate::transport::{ACCESS_VIOLATION, DeviceTransport, IoctlStatus};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Decides how the mock driver replies to an IOCTL, given what was sent
+pub type Handler = dyn Fn(&SentIoctl) -> MockReply + Send + Sync;
+
+/// An IOCTL that was sent through a `MockTransport`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentIoctl {
+    pub device_name: String,
+    /// Access that the handle the IOCTL was sent through was opened with
+    pub desired_access: u32,
+    pub code: u32,
+    /// Contents of the input buffer, or None if it was NULL
+    pub input_buffer: Option<Vec<u8>>,
+    pub input_buffer_size: usize,
+    pub output_buffer_size: usize,
+}
+
+/// How the mock driver completes an IOCTL. The default reply succeeds without writing
+/// anything.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct MockReply {
+    /// Bytes written to the start of the output buffer, truncated to fit, and given as a hex
+    /// string in TOML
+    #[serde(default, deserialize_with = "crate::deserialize_hex")]
+    pub output: Vec<u8>,
+    /// Status that the IOCTL completes with
+    #[serde(default)]
+    pub status: i32,
+    /// Number of bytes that the driver reports having written, if not the number actually
+    /// written. Buggy drivers can report more than the output buffer holds.
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    pub bytes_returned: Option<usize>,
+    /// A fault to simulate instead of replying normally
+    pub fault: Option<MockFault>,
+}
+
+/// Ways that the mock driver can go wrong
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MockFault {
+    /// The driver touched memory outside of the buffers it was given, e.g. one of our guard
+    /// pages, and the fault was caught and returned as an error status
+    AccessViolation,
+    /// The driver crashed the machine. The IOCTL never completes, and the device can't be
+    /// opened or sent to again.
+    Crash,
+}
+
+/// A mock driver declared in TOML, as a list of handlers that reply to the IOCTL codes
+/// they are for, e.g.
+///
+/// ```toml
+/// [[handlers]]
+/// code = 0x222000
+/// output = "0000000000f8ffff"
+///
+/// [[handlers]]
+/// code = 0x222004
+/// replies = [{ status = 31 }, { fault = "crash" }]
+/// ```
+#[derive(Debug, Deserialize)]
+struct MockDriver {
+    /// Reply to IOCTLs without a handler
+    #[serde(default)]
+    default: MockReply,
+    #[serde(default)]
+    handlers: Vec<HandlerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HandlerConfig {
+    #[serde(deserialize_with = "crate::ctl_code::deserialize_code")]
+    code: u32,
+    /// Replies to give in turn, with the last one given from then on. If empty, the reply
+    /// given in the handler's own fields is always given.
+    #[serde(default)]
+    replies: Vec<MockReply>,
+    #[serde(flatten)]
+    reply: MockReply,
+}
+
+/// In-process stand-in for a device, so that dispatchers can run without a driver, e.g. in
+/// CI. Every IOCTL sent through it is recorded, and replied to by the handler for its code,
+/// or else with the default reply, which succeeds without writing anything.
+///
+/// Handlers can be Rust closures, or declared in TOML with `from_toml`. Clones share the
+/// same record, so a clone can be given to a dispatcher and the original inspected once it
+/// has finished.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    default_reply: MockReply,
+    handlers: HashMap<u32, Arc<Handler>>,
+    max_opens: Option<usize>,
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    opens: usize,
+    open_handles: usize,
+    crashed: bool,
+    sent: Vec<SentIoctl>,
+}
+
+/// Handle to a device opened through a `MockTransport`
+#[derive(Debug)]
+pub struct MockHandle {
+    device_name: String,
+    desired_access: u32,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Builds a mock driver from its TOML declaration. See `MockDriver` for the format.
+    pub fn from_toml(contents: &str) -> Result<MockTransport, toml::de::Error> {
+        let driver: MockDriver = toml::from_str(contents)?;
+
+        let mut transport = MockTransport {
+            default_reply: driver.default,
+            ..Default::default()
+        };
+
+        for handler in driver.handlers {
+            transport = match handler.replies.is_empty() {
+                true => transport.reply(handler.code, handler.reply),
+                false => transport.replies(handler.code, handler.replies),
+            };
+        }
+
+        Ok(transport)
+    }
+
+    /// Bytes that the driver returns at the start of the output buffer for IOCTLs without a
+    /// handler, truncated to fit
+    pub fn output(mut self, output: &[u8]) -> MockTransport {
+        self.default_reply.output = output.to_vec();
+        self
+    }
+
+    /// Status that IOCTLs without a handler complete with
+    pub fn status(mut self, status: i32) -> MockTransport {
+        self.default_reply.status = status;
+        self
+    }
+
+    /// How many handles can be opened before opening fails. Fuzzing dispatchers run until
+    /// they hit an error, so this is one way of stopping them.
+    pub fn max_opens(mut self, max_opens: usize) -> MockTransport {
+        self.max_opens = Some(max_opens);
+        self
+    }
+
+    /// Replies to every IOCTL with `code` by calling `handler`, which can keep its own state
+    /// to script a sequence of replies
+    pub fn handler<F>(mut self, code: u32, handler: F) -> MockTransport
+    where
+        F: Fn(&SentIoctl) -> MockReply + Send + Sync + 'static,
+    {
+        self.handlers.insert(code, Arc::new(handler));
+        self
+    }
+
+    /// Replies to every IOCTL with `code` with the same reply
+    pub fn reply(self, code: u32, reply: MockReply) -> MockTransport {
+        self.handler(code, move |_| reply.clone())
+    }
+
+    /// Replies to the IOCTLs with `code` with each of `replies` in turn, and then with the
+    /// last one from then on
+    pub fn replies(self, code: u32, replies: Vec<MockReply>) -> MockTransport {
+        let next = AtomicUsize::new(0);
+
+        self.handler(code, move |_| {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            replies
+                .get(i.min(replies.len().saturating_sub(1)))
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    /// Every IOCTL sent so far, in the order they were sent
+    pub fn sent(&self) -> Vec<SentIoctl> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Number of handles that have been opened and not yet closed
+    pub fn open_handles(&self) -> usize {
+        self.state.lock().unwrap().open_handles
+    }
+
+    /// Whether a handler has simulated a crash
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+}
+
+impl fmt::Debug for MockTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut codes: Vec<_> = self.handlers.keys().collect();
+        codes.sort();
+
+        f.debug_struct("MockTransport")
+            .field("default_reply", &self.default_reply)
+            .field("handlers", &codes)
+            .field("max_opens", &self.max_opens)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl DeviceTransport for MockTransport {
+    type Handle = MockHandle;
+
+    fn open(
+        &self,
+        device_name: &str,
+        options: &OpenOptions,
+        _overlapped: bool,
+    ) -> io::Result<MockHandle> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.crashed {
+            return Err(io::Error::other("mock driver has crashed"));
+        }
+
+        if self
+            .max_opens
+            .is_some_and(|max_opens| state.opens >= max_opens)
+        {
+            return Err(io::Error::other(
+                "mock device has been opened too many times",
+            ));
+        }
+
+        state.opens += 1;
+        state.open_handles += 1;
+
+        Ok(MockHandle {
+            device_name: device_name.to_string(),
+            desired_access: options.desired_access_or_default(),
+        })
+    }
+
+    fn ioctl(
+        &self,
+        handle: &MockHandle,
+        code: u32,
+        input_buffer: Option<&[u8]>,
+        input_buffer_size: usize,
+        output_buffer: Option<&mut [u8]>,
+        output_buffer_size: usize,
+    ) -> io::Result<IoctlStatus> {
+        let sent_ioctl = SentIoctl {
+            device_name: handle.device_name.clone(),
+            desired_access: handle.desired_access,
+            code,
+            input_buffer: input_buffer.map(<[u8]>::to_vec),
+            input_buffer_size,
+            output_buffer_size,
+        };
+
+        if self.state.lock().unwrap().crashed {
+            return Err(io::Error::other("mock driver has crashed"));
+        }
+
+        // The handler is called without holding the lock, so that it can inspect the record
+        let reply = match self.handlers.get(&code) {
+            Some(handler) => handler(&sent_ioctl),
+            None => self.default_reply.clone(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.sent.push(sent_ioctl);
+
+        match reply.fault {
+            Some(MockFault::AccessViolation) => {
+                return Ok(IoctlStatus {
+                    bytes_returned: 0,
+                    status: ACCESS_VIOLATION,
+                });
+            }
+            Some(MockFault::Crash) => {
+                state.crashed = true;
+                return Err(io::Error::other("mock driver has crashed"));
+            }
+            None => (),
+        }
+
+        let written = match output_buffer {
+            Some(output_buffer) => {
+                let len = reply
+                    .output
+                    .len()
+                    .min(output_buffer.len())
+                    .min(output_buffer_size);
+                output_buffer[..len].copy_from_slice(&reply.output[..len]);
+                len
+            }
+            None => 0,
+        };
+
+        Ok(IoctlStatus {
+            bytes_returned: reply.bytes_returned.unwrap_or(written),
+            status: reply.status,
+        })
+    }
+
+    fn close(&self, _handle: MockHandle) -> io::Result<()> {
+        self.state.lock().unwrap().open_handles -= 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(transport: &MockTransport, code: u32, output_size: usize) -> io::Result<IoctlStatus> {
+        let handle = transport.open(r"\\.\Mock", &OpenOptions::default(), false)?;
+        let mut output_buffer = vec![0; output_size];
+
+        let status = transport.ioctl(
+            &handle,
+            code,
+            None,
+            0,
+            Some(&mut output_buffer),
+            output_size,
+        );
+        transport.close(handle)?;
+
+        status
+    }
+
+    #[test]
+    fn mock_driver_from_toml() {
+        let transport = MockTransport::from_toml(
+            r#"default = { status = 1 }
+
+[[handlers]]
+code = 0x222000
+output = "41414141"
+bytes_returned = 0x100
+
+[[handlers]]
+code = { device_type = 0x22, function = 0x801 }
+replies = [{ status = 31 }, {}, { fault = "access_violation" }]
+
+[[handlers]]
+code = 0x222008
+fault = "crash"
+"#,
+        )
+        .unwrap();
+
+        let status = send(&transport, 0x222000, 0x8).unwrap();
+        assert_eq!(0x100, status.bytes_returned);
+        assert!(status.succeeded());
+
+        assert_eq!(1, send(&transport, 0x1, 0x0).unwrap().status);
+
+        assert_eq!(31, send(&transport, 0x222004, 0x0).unwrap().status);
+        assert!(send(&transport, 0x222004, 0x0).unwrap().succeeded());
+        assert!(
+            send(&transport, 0x222004, 0x0)
+                .unwrap()
+                .is_access_violation()
+        );
+        assert!(
+            send(&transport, 0x222004, 0x0)
+                .unwrap()
+                .is_access_violation()
+        );
+
+        assert!(!transport.crashed());
+        assert!(send(&transport, 0x222008, 0x0).is_err());
+        assert!(transport.crashed());
+        assert!(send(&transport, 0x222000, 0x8).is_err());
+
+        assert_eq!(7, transport.sent().len());
+        assert_eq!(0, transport.open_handles());
+
+        assert!(MockTransport::from_toml("[[handlers]]\ncode = 0x1\noutput = \"414\"").is_err());
+    }
+
+    #[test]
+    fn mock_driver_closure_handlers() {
+        // Only replies with data once the device has been initialised
+        let transport = MockTransport::new().reply(0x1, MockReply::default());
+        let record = transport.clone();
+        let transport = transport.handler(0x2, move |_| {
+            match record
+                .sent()
+                .iter()
+                .any(|sent_ioctl| sent_ioctl.code == 0x1)
+            {
+                true => MockReply {
+                    output: vec![0xAA; 4],
+                    ..Default::default()
+                },
+                false => MockReply {
+                    status: 21,
+                    ..Default::default()
+                },
+            }
+        });
+
+        assert_eq!(21, send(&transport, 0x2, 0x4).unwrap().status);
+        assert!(send(&transport, 0x1, 0x0).unwrap().succeeded());
+
+        let status = send(&transport, 0x2, 0x2).unwrap();
+        assert!(status.succeeded());
+        assert_eq!(2, status.bytes_returned);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::expr;
+use serde::Deserialize;
+
+/// Standard `CreateFileW` constants, which can be used by name in any expression, e.g.
+/// `desired_access = "GENERIC_READ | SYNCHRONIZE"`. Values in `[vars]` take precedence.
+pub const CONSTANTS: &[(&str, u32)] = &[
+    ("GENERIC_READ", 0x8000_0000),
+    ("GENERIC_WRITE", 0x4000_0000),
+    ("GENERIC_EXECUTE", 0x2000_0000),
+    ("GENERIC_ALL", 0x1000_0000),
+    ("MAXIMUM_ALLOWED", 0x0200_0000),
+    ("SYNCHRONIZE", 0x0010_0000),
+    ("READ_CONTROL", 0x0002_0000),
+    ("FILE_READ_DATA", 0x1),
+    ("FILE_WRITE_DATA", 0x2),
+    ("FILE_APPEND_DATA", 0x4),
+    ("FILE_READ_ATTRIBUTES", 0x80),
+    ("FILE_WRITE_ATTRIBUTES", 0x100),
+    ("FILE_SHARE_NONE", 0x0),
+    ("FILE_SHARE_READ", 0x1),
+    ("FILE_SHARE_WRITE", 0x2),
+    ("FILE_SHARE_DELETE", 0x4),
+    ("CREATE_NEW", 1),
+    ("CREATE_ALWAYS", 2),
+    ("OPEN_EXISTING", 3),
+    ("OPEN_ALWAYS", 4),
+    ("TRUNCATE_EXISTING", 5),
+    ("FILE_ATTRIBUTE_NORMAL", 0x80),
+    ("FILE_FLAG_WRITE_THROUGH", 0x8000_0000),
+    ("FILE_FLAG_OVERLAPPED", 0x4000_0000),
+    ("FILE_FLAG_NO_BUFFERING", 0x2000_0000),
+    ("FILE_FLAG_BACKUP_SEMANTICS", 0x0200_0000),
+    ("FILE_FLAG_OPEN_REPARSE_POINT", 0x0020_0000),
+];
+
+/// Access rights that `describe_access_mask` names, most significant first
+const ACCESS_RIGHTS: [&str; 12] = [
+    "GENERIC_READ",
+    "GENERIC_WRITE",
+    "GENERIC_EXECUTE",
+    "GENERIC_ALL",
+    "MAXIMUM_ALLOWED",
+    "SYNCHRONIZE",
+    "READ_CONTROL",
+    "FILE_WRITE_ATTRIBUTES",
+    "FILE_READ_ATTRIBUTES",
+    "FILE_APPEND_DATA",
+    "FILE_WRITE_DATA",
+    "FILE_READ_DATA",
+];
+
+/// Access masks that an IOCTL is replayed with by `AccessMatrixDispatcher`, chosen to show
+/// whether the driver relies on the access bits in the IOCTL code. The IOCTL's own
+/// `desired_access` is tried as well.
+pub const DEFAULT_ACCESS_MATRIX: [u32; 7] = [
+    0x0,
+    0x80,        // FILE_READ_ATTRIBUTES
+    0x1,         // FILE_READ_DATA
+    0x2,         // FILE_WRITE_DATA
+    0x8000_0000, // GENERIC_READ
+    0x4000_0000, // GENERIC_WRITE
+    0xC000_0000, // GENERIC_READ | GENERIC_WRITE
+];
+
+/// Looks up one of the `CreateFileW` constants by name
+pub fn constant(name: &str) -> Option<u32> {
+    CONSTANTS
+        .iter()
+        .find(|(constant_name, _)| *constant_name == name)
+        .map(|(_, value)| *value)
+}
+
+/// Arguments to `CreateFileW` when opening a device. Any left unset fall back to the
+/// device's options, and then to the defaults of the `*_or_default` getters.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct OpenOptions {
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    pub desired_access: Option<u32>,
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    pub share_mode: Option<u32>,
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    pub creation_disposition: Option<u32>,
+    /// Flags and attributes, to which `FILE_FLAG_OVERLAPPED` is added for overlapped IOCTLs
+    #[serde(default, deserialize_with = "expr::deserialize_option_number")]
+    pub flags_and_attributes: Option<u32>,
+}
+
+impl OpenOptions {
+    /// Fills in any options that are unset with those from `fallback`
+    pub fn or(&self, fallback: &OpenOptions) -> OpenOptions {
+        OpenOptions {
+            desired_access: self.desired_access.or(fallback.desired_access),
+            share_mode: self.share_mode.or(fallback.share_mode),
+            creation_disposition: self.creation_disposition.or(fallback.creation_disposition),
+            flags_and_attributes: self.flags_and_attributes.or(fallback.flags_and_attributes),
+        }
+    }
+
+    /// Defaults to `GENERIC_READ | GENERIC_WRITE`
+    pub fn desired_access_or_default(&self) -> u32 {
+        self.desired_access.unwrap_or(0xC000_0000)
+    }
+
+    /// Defaults to `FILE_SHARE_NONE`
+    pub fn share_mode_or_default(&self) -> u32 {
+        self.share_mode.unwrap_or(0)
+    }
+
+    /// Defaults to `OPEN_EXISTING`
+    pub fn creation_disposition_or_default(&self) -> u32 {
+        self.creation_disposition.unwrap_or(3)
+    }
+
+    /// Defaults to `FILE_ATTRIBUTE_NORMAL`
+    pub fn flags_and_attributes_or_default(&self) -> u32 {
+        self.flags_and_attributes.unwrap_or(0x80)
+    }
+}
+
+/// Whether a handle opened with `mask` can read data. `MAXIMUM_ALLOWED` is counted as
+/// granting everything, as it usually does for an administrator.
+pub fn grants_read(mask: u32) -> bool {
+    mask & (0x1 | 0x8000_0000 | 0x1000_0000 | 0x0200_0000) != 0
+}
+
+/// Whether a handle opened with `mask` can write data, counted as for `grants_read`
+pub fn grants_write(mask: u32) -> bool {
+    mask & (0x2 | 0x4 | 0x4000_0000 | 0x1000_0000 | 0x0200_0000) != 0
+}
+
+/// Names the access rights in a mask, e.g. `GENERIC_READ | SYNCHRONIZE`. Any bits without a
+/// name are given in hex.
+pub fn describe_access_mask(mask: u32) -> String {
+    if mask == 0 {
+        return "0".to_string();
+    }
+
+    let mut remaining = mask;
+    let mut names: Vec<String> = vec![];
+
+    for name in ACCESS_RIGHTS {
+        let bit = constant(name).unwrap_or_default();
+        if remaining & bit != 0 {
+            names.push(name.to_string());
+            remaining &= !bit;
+        }
+    }
+
+    if remaining != 0 {
+        names.push(format!("0x{remaining:X}"));
+    }
+
+    names.join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_masks() {
+        assert_eq!("0", describe_access_mask(0));
+        assert_eq!(
+            "GENERIC_READ | GENERIC_WRITE",
+            describe_access_mask(0xC000_0000)
+        );
+        assert_eq!(
+            "SYNCHRONIZE | FILE_READ_ATTRIBUTES | 0x8",
+            describe_access_mask(0x0010_0088)
+        );
+
+        assert!(grants_read(0x8000_0000) && !grants_write(0x8000_0000));
+        assert!(!grants_read(0x80) && !grants_write(0x80));
+        assert!(grants_read(0x1000_0000) && grants_write(0x1000_0000));
+    }
+
+    #[test]
+    fn open_options_fall_back() {
+        let ioctl_options = OpenOptions {
+            desired_access: Some(0x8000_0000),
+            ..Default::default()
+        };
+        let device_options = OpenOptions {
+            desired_access: Some(0x4000_0000),
+            share_mode: Some(0x3),
+            ..Default::default()
+        };
+
+        let options = ioctl_options.or(&device_options);
+        assert_eq!(0x8000_0000, options.desired_access_or_default());
+        assert_eq!(0x3, options.share_mode_or_default());
+        assert_eq!(3, options.creation_disposition_or_default());
+        assert_eq!(0x80, options.flags_and_attributes_or_default());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::dispatch::DispatchOutcome;
+use crate::open_options;
+use std::sync::Mutex;
+
+/// Receives the outcome of each IOCTL as soon as a dispatcher has sent it. When fuzzing,
+/// every thread reports to the same reporter.
+pub trait Reporter: Send + Sync {
+    fn report(&self, outcome: DispatchOutcome);
+}
+
+/// Collects outcomes, so that they can be looked at once the dispatcher has finished
+impl Reporter for Mutex<Vec<DispatchOutcome>> {
+    fn report(&self, outcome: DispatchOutcome) {
+        self.lock().unwrap().push(outcome);
+    }
+}
+
+/// Prints everything about each outcome, for IOCTLs sent one at a time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrintReporter;
+
+impl Reporter for PrintReporter {
+    fn report(&self, outcome: DispatchOutcome) {
+        print_overlaps(&outcome);
+
+        if let Some(e) = &outcome.open_error {
+            println!("Failed to open {}: {e}", outcome.device_name);
+            return;
+        }
+
+        println!("{} completed in {:?}", outcome.ioctl, outcome.duration);
+
+        // Drivers can over-report bytes returned whatever the status, and the I/O manager
+        // copies that many bytes back to us
+        print_overreported_bytes_returned(&outcome);
+
+        if outcome.guard_page_fault {
+            print_guard_page_fault(&outcome);
+            return;
+        }
+
+        if let Some(e) = outcome.status.error() {
+            println!("DeviceIoControl failed: {e}");
+            return;
+        }
+
+        println!("DeviceIoControl called successfully.");
+
+        match outcome.output.as_deref() {
+            Some(output_buffer) if !output_buffer.is_empty() => {
+                let returned = outcome.returned_output();
+                let rest = outcome.rest_of_output();
+
+                println!(
+                    "Returned (0x{:X} bytes):\n{:X?}\n",
+                    outcome.status.bytes_returned, returned
+                );
+
+                if !rest.is_empty() {
+                    println!(
+                        "Rest of output buffer (0x{:X} bytes, not returned):\n{:X?}\n",
+                        rest.len(),
+                        rest
+                    );
+                }
+
+                print_leaks(&outcome);
+            }
+            _ => println!("No output buffer received"),
+        }
+    }
+}
+
+/// Prints only guard page faults and possible info leaks, for fuzzing, where almost every
+/// outcome is uninteresting
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FindingsReporter;
+
+impl Reporter for FindingsReporter {
+    fn report(&self, outcome: DispatchOutcome) {
+        print_overlaps(&outcome);
+
+        if outcome.guard_page_fault {
+            print_guard_page_fault(&outcome);
+        }
+
+        print_overreported_bytes_returned(&outcome);
+        print_leaks(&outcome);
+    }
+}
+
+/// Prints a line for each access mask that an IOCTL was replayed with, saying whether the
+/// driver accepted it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccessMatrixReporter;
+
+impl Reporter for AccessMatrixReporter {
+    fn report(&self, outcome: DispatchOutcome) {
+        print_overlaps(&outcome);
+
+        let access_mask = outcome.desired_access;
+
+        let description = match (&outcome.open_error, outcome.status.error()) {
+            (Some(e), _) => format!("open failed: {e}"),
+            (None, None)
+                if !open_options::grants_read(access_mask)
+                    && !open_options::grants_write(access_mask) =>
+            {
+                "succeeded without read or write access".to_string()
+            }
+            (None, None) if !open_options::grants_write(access_mask) => {
+                "succeeded without write access".to_string()
+            }
+            (None, None) => "succeeded".to_string(),
+            (None, Some(_)) if outcome.guard_page_fault => {
+                print_guard_page_fault(&outcome);
+                "faulted on a guard page".to_string()
+            }
+            (None, Some(e)) => format!("failed: {e}"),
+        };
+
+        println!(
+            "{:<45} {description}",
+            open_options::describe_access_mask(access_mask)
+        );
+
+        print_overreported_bytes_returned(&outcome);
+    }
+}
+
+/// Warns about overlapping entries in the buffers, which are usually a mistake in the
+/// config rather than anything the driver did
+fn print_overlaps(outcome: &DispatchOutcome) {
+    for overlap in &outcome.overlaps {
+        eprintln!("Warning: {overlap}");
+    }
+}
+
+/// Reports that the driver accessed memory outside of the buffers it was given, along with
+/// the input that caused it
+fn print_guard_page_fault(outcome: &DispatchOutcome) {
+    println!(
+        "Finding: {} faulted on a guard page, so the driver accessed memory outside of its buffers",
+        outcome.ioctl
+    );
+
+    print_input(outcome);
+}
+
+/// Reports a driver that claims to have returned more bytes than the output buffer holds,
+/// along with the input that caused it
+fn print_overreported_bytes_returned(outcome: &DispatchOutcome) {
+    if !outcome.overreported_bytes_returned() {
+        return;
+    }
+
+    println!(
+        "Finding: {} returned 0x{:X} bytes, more than its output buffer holds (0x{:X} bytes allocated, 0x{:X} declared)",
+        outcome.ioctl,
+        outcome.status.bytes_returned,
+        outcome.allocated_output_size(),
+        outcome.output_buffer_size
+    );
+
+    print_input(outcome);
+}
+
+fn print_input(outcome: &DispatchOutcome) {
+    match outcome.input.as_deref() {
+        Some(input_buffer) => println!("Input:\n{:X?}\n", input_buffer),
+        None => println!("Input: NULL\n"),
+    }
+}
+
+fn print_leaks(outcome: &DispatchOutcome) {
+    for leak in &outcome.leaks {
+        println!("Possible leak at {}: {}", leak.0, leak.1);
+    }
+}
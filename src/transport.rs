@@ -0,0 +1,117 @@
+use crate::open_options::OpenOptions;
+use std::io;
+
+/// Status that a driver completes an IOCTL with when it faults on a user buffer, e.g. one of
+/// our guard pages. Drivers that probe user buffers turn the fault into
+/// STATUS_ACCESS_VIOLATION, which comes back to us as ERROR_NOACCESS.
+#[cfg(windows)]
+pub(crate) const ACCESS_VIOLATION: i32 = 998;
+/// EFAULT
+#[cfg(not(windows))]
+pub(crate) const ACCESS_VIOLATION: i32 = 14;
+
+#[cfg(target_os = "linux")]
+pub use crate::linux_helpers::LinuxTransport as NativeTransport;
+/// The transport that dispatchers send IOCTLs through when running against a real device
+#[cfg(windows)]
+pub use crate::win_helpers::WindowsTransport as NativeTransport;
+#[cfg(not(any(windows, target_os = "linux")))]
+pub use UnsupportedTransport as NativeTransport;
+
+/// Somewhere that IOCTLs can be sent, which is a real device on the target machine, or a
+/// mock when testing the rest of the tool without a driver. Dispatchers only talk to devices
+/// through this, so none of them depend on the platform they run on.
+pub trait DeviceTransport {
+    /// An open handle to a device
+    type Handle;
+
+    /// Opens a handle to the device with the given `CreateFileW` options. Overlapped IOCTLs
+    /// need a handle opened for overlapped I/O.
+    fn open(
+        &self,
+        device_name: &str,
+        options: &OpenOptions,
+        overlapped: bool,
+    ) -> io::Result<Self::Handle>;
+
+    /// Sends an IOCTL with the given buffers. The sizes passed are independent of the
+    /// lengths of the buffers, so that size confusion can be tested, and a None buffer is
+    /// passed as NULL.
+    ///
+    /// An IOCTL that reaches the device and fails is returned as a status rather than an
+    /// error, which is kept for failing to send it at all.
+    fn ioctl(
+        &self,
+        handle: &Self::Handle,
+        code: u32,
+        input_buffer: Option<&[u8]>,
+        input_buffer_size: usize,
+        output_buffer: Option<&mut [u8]>,
+        output_buffer_size: usize,
+    ) -> io::Result<IoctlStatus>;
+
+    /// Closes a handle returned by `open`
+    fn close(&self, handle: Self::Handle) -> io::Result<()>;
+}
+
+/// How an IOCTL sent through a transport completed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoctlStatus {
+    /// Number of bytes that the driver reported writing to the output buffer
+    pub bytes_returned: usize,
+    /// OS error code that the IOCTL failed with, which is a Win32 error code on Windows and
+    /// an errno elsewhere, or 0 if it succeeded
+    pub status: i32,
+}
+
+impl IoctlStatus {
+    pub fn succeeded(&self) -> bool {
+        self.status == 0
+    }
+
+    /// Returns the failure as an `io::Error`, so that it is described the way the OS
+    /// describes it
+    pub fn error(&self) -> Option<io::Error> {
+        match self.status {
+            0 => None,
+            status => Some(io::Error::from_raw_os_error(status)),
+        }
+    }
+
+    /// Whether the driver faulted on one of the user buffers it was given
+    pub fn is_access_violation(&self) -> bool {
+        self.status == ACCESS_VIOLATION
+    }
+}
+
+/// Transport for platforms that have no way of sending IOCTLs, which fails to open any
+/// device. Everything that doesn't send IOCTLs, such as validating configs, still works.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnsupportedTransport;
+
+impl DeviceTransport for UnsupportedTransport {
+    type Handle = ();
+
+    fn open(&self, _: &str, _: &OpenOptions, _: bool) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "sending IOCTLs is not supported on this platform",
+        ))
+    }
+
+    fn ioctl(
+        &self,
+        _: &(),
+        _: u32,
+        _: Option<&[u8]>,
+        _: usize,
+        _: Option<&mut [u8]>,
+        _: usize,
+    ) -> io::Result<IoctlStatus> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn close(&self, _: ()) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use crate::allocator::GuardPage;
+use crate::ctl_code::{Access, Method};
+use crate::error::BufferError;
+use crate::open_options::{self, describe_access_mask};
+use crate::{BufferSpec, Config, Ioctl, find_overlaps};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// How serious a validation diagnostic is. Errors mean the IOCTL can't be sent as
+/// configured, whereas warnings point at something that is probably a mistake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found by `Config::validate`, with where in the config it comes from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Name of the IOCTL that the problem is in
+    pub ioctl: String,
+    /// Config file that the IOCTL was declared in, if known
+    pub file: Option<PathBuf>,
+    /// Line of the config file that the problem is on, if known
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.ioctl)?;
+
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, " ({} line {line})", file.display())?,
+            (Some(file), None) => write!(f, " ({})", file.display())?,
+            (None, Some(line)) => write!(f, " (line {line})")?,
+            (None, None) => (),
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl Config {
+    /// Checks every IOCTL for problems that deserializing the config can't catch, without
+    /// opening any devices or allocating any buffers. Diagnostics are returned in the order
+    /// the IOCTLs are declared.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut codes: HashMap<(&str, u32), &Ioctl> = HashMap::new();
+
+        for ioctl in &self.ioctls {
+            if let Some(first) = codes.insert((ioctl.device_name(), ioctl.code), ioctl) {
+                diagnostics.push(ioctl.diagnostic(
+                    Severity::Warning,
+                    ioctl.line,
+                    format!(
+                        "code 0x{:X} is already used by {} on the same device",
+                        ioctl.code, first.name
+                    ),
+                ));
+            }
+
+            diagnostics.extend(ioctl.validate());
+        }
+
+        diagnostics
+    }
+}
+
+impl Ioctl {
+    /// Checks the IOCTL's buffers, and its code against how it is sent
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        let input_spec = self.input_spec();
+        if self.null_input && !input_spec.content.is_empty() {
+            diagnostics.push(self.diagnostic(
+                Severity::Warning,
+                input_spec.entry_lines.first().copied(),
+                "null_input is set, so input_buffer_content is never sent".to_string(),
+            ));
+        } else {
+            self.validate_buffer(&input_spec, &mut diagnostics);
+        }
+
+        let mut aux_buffers: Vec<_> = self.aux_buffers.iter().collect();
+        aux_buffers.sort_by_key(|(aux_name, _)| *aux_name);
+
+        for (aux_name, aux_buffer) in aux_buffers {
+            self.validate_buffer(&self.aux_spec(aux_name, aux_buffer), &mut diagnostics);
+        }
+
+        self.validate_ctl_code(&mut diagnostics);
+
+        diagnostics
+    }
+
+    /// Lays out a buffer's entries, reporting every entry that can't be laid out, and any
+    /// overlapping entries among the rest
+    fn validate_buffer(&self, spec: &BufferSpec, diagnostics: &mut Vec<Diagnostic>) {
+        if spec.size == 0 && !spec.content.is_empty() {
+            diagnostics.push(self.diagnostic(
+                Severity::Error,
+                spec.entry_lines.first().copied(),
+                format!("{spec} has content but a size of 0"),
+            ));
+            return;
+        }
+
+        let mut errors = Vec::new();
+        let laid_out_entries =
+            self.lay_out_valid_entries(spec, &self.unallocated_addresses(), &mut errors);
+        diagnostics.extend(errors.into_iter().map(buffer_error_diagnostic));
+
+        let severity = match self.strict {
+            true => Severity::Error,
+            false => Severity::Warning,
+        };
+
+        for overlap in find_overlaps(&laid_out_entries) {
+            diagnostics.push(self.diagnostic(
+                severity,
+                spec.entry_lines.get(overlap.second).copied(),
+                format!("{spec} {overlap}"),
+            ));
+        }
+    }
+
+    /// Checks that the transfer method and required access in the code make sense with how
+    /// the IOCTL is configured to be sent. Codes for Linux devices have neither, so aren't
+    /// checked.
+    fn validate_ctl_code(&self, diagnostics: &mut Vec<Diagnostic>) {
+        if self.is_linux_device() {
+            return;
+        }
+
+        let ctl_code = self.ctl_code();
+        let desired_access = self.open_options.desired_access_or_default();
+
+        let needs_read = matches!(ctl_code.access, Access::Read | Access::ReadWrite);
+        let needs_write = matches!(ctl_code.access, Access::Write | Access::ReadWrite);
+
+        if (needs_read && !open_options::grants_read(desired_access))
+            || (needs_write && !open_options::grants_write(desired_access))
+        {
+            diagnostics.push(self.diagnostic(
+                Severity::Warning,
+                self.line,
+                format!(
+                    "code requires {} but the device is opened with {}, so the I/O manager will reject it",
+                    ctl_code.access,
+                    describe_access_mask(desired_access)
+                ),
+            ));
+        }
+
+        // The I/O manager copies METHOD_BUFFERED buffers, so the driver never touches ours
+        if ctl_code.method == Method::Buffered && self.guard_page != GuardPage::None {
+            diagnostics.push(self.diagnostic(
+                Severity::Warning,
+                self.line,
+                "guard_page is meaningless with METHOD_BUFFERED, as the driver only sees a copy of the buffers".to_string(),
+            ));
+        }
+
+        if matches!(ctl_code.method, Method::InDirect | Method::OutDirect)
+            && (self.null_output || self.declared_output_buffer_size() == 0)
+        {
+            diagnostics.push(self.diagnostic(
+                Severity::Warning,
+                self.line,
+                format!(
+                    "{} has no output buffer, so the driver is given no MDL",
+                    ctl_code.method
+                ),
+            ));
+        }
+    }
+
+    fn diagnostic(&self, severity: Severity, line: Option<usize>, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            ioctl: self.name.clone(),
+            file: self.config_file.clone(),
+            line,
+            message,
+        }
+    }
+}
+
+/// Turns an error building one of an IOCTL's buffers into an error diagnostic
+fn buffer_error_diagnostic(e: BufferError) -> Diagnostic {
+    let buffer = match &e.aux_buffer {
+        Some(aux_buffer) => format!("aux buffer {aux_buffer}"),
+        None => "input buffer".to_string(),
+    };
+
+    Diagnostic {
+        severity: Severity::Error,
+        ioctl: e.ioctl,
+        file: e.file.map(PathBuf::from),
+        line: e.line,
+        message: format!("{buffer} entry {}: {}", e.entry, e.kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use std::fs;
+
+    #[test]
+    fn validate_reports_problems_with_lines() {
+        let config_path = std::env::temp_dir().join("ioctiller_validate.toml");
+        fs::write(
+            &config_path,
+            r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "OUT_OF_BOUNDS"
+code = 0x222000
+input_buffer_size = 0x8
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "U32", value = 1 },
+    { offset = 0x6, type = "U32", value = 1 },
+    { offset = 0x4, type = "SizeOf", target = "missing" },
+]
+
+[[ioctls]]
+name = "OVERLAP"
+code = 0x222004
+input_buffer_size = 0x8
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "U32", value = 1 },
+    { offset = 0x2, type = "U16", value = 1 },
+]
+
+[[ioctls]]
+name = "ZERO_SIZE"
+code = 0x222008
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "U8", value = 1 },
+]
+
+[[ioctls]]
+name = "WRITE_ACCESS"
+code = { device_type = 0x22, function = 0x803, method = "out_direct", access = "write" }
+desired_access = "GENERIC_READ"
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "SAME_CODE"
+code = 0x222000
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "BUFFERED_GUARD"
+code = 0x22200C
+input_buffer_size = 0x8
+output_buffer_size = 0x0
+declared_input_buffer_size = 0x1000
+guard_page = "end"
+"#,
+        )
+        .unwrap();
+
+        let config = Config::build(&Cli {
+            file_path: config_path.clone(),
+            validate: true,
+            format: None,
+        })
+        .unwrap();
+        fs::remove_file(&config_path).unwrap();
+
+        let diagnostics = config.validate();
+
+        assert_eq!(
+            vec![
+                (Severity::Error, "OUT_OF_BOUNDS", Some(10)),
+                (Severity::Error, "OUT_OF_BOUNDS", Some(11)),
+                (Severity::Warning, "OVERLAP", Some(21)),
+                (Severity::Error, "ZERO_SIZE", Some(30)),
+                (Severity::Warning, "WRITE_ACCESS", Some(33)),
+                (Severity::Warning, "WRITE_ACCESS", Some(33)),
+                (Severity::Warning, "SAME_CODE", Some(40)),
+                (Severity::Warning, "BUFFERED_GUARD", Some(46)),
+            ],
+            diagnostics
+                .iter()
+                .map(|diagnostic| (
+                    diagnostic.severity,
+                    diagnostic.ioctl.as_str(),
+                    diagnostic.line
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        let messages: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+        assert!(
+            messages[0].starts_with("error: OUT_OF_BOUNDS (")
+                && messages[0].ends_with("line 10): input buffer entry 1: 0x4 bytes at offset 0x6 do not fit in the 0x8 byte input buffer"),
+            "{}",
+            messages[0]
+        );
+        // Every entry that can't be laid out is reported, not just the first
+        assert!(
+            messages[1]
+                .ends_with("line 11): input buffer entry 2: Computed entry target not found"),
+            "{}",
+            messages[1]
+        );
+        assert!(
+            messages[4].contains("requires FILE_WRITE_ACCESS"),
+            "{}",
+            messages[4]
+        );
+        assert!(
+            messages[5].contains("METHOD_OUT_DIRECT has no output buffer"),
+            "{}",
+            messages[5]
+        );
+        assert!(
+            messages[6].contains("already used by OUT_OF_BOUNDS"),
+            "{}",
+            messages[6]
+        );
+        assert!(
+            messages[7].ends_with(
+                "guard_page is meaningless with METHOD_BUFFERED, as the driver only sees a copy of the buffers"
+            ),
+            "{}",
+            messages[7]
+        );
+    }
+
+    #[test]
+    fn validate_duplicate_names_fail_to_build() {
+        // Duplicate names are caught when the config is built, so never reach validate
+        let config_path = std::env::temp_dir().join("ioctiller_validate_duplicate.toml");
+        fs::write(
+            &config_path,
+            r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = 0x222000
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+
+[[ioctls]]
+name = "IOCTL_TEST"
+code = 0x222004
+input_buffer_size = 0x0
+output_buffer_size = 0x0
+"#,
+        )
+        .unwrap();
+
+        let error = Config::build(&Cli {
+            file_path: config_path.clone(),
+            validate: true,
+            format: None,
+        })
+        .unwrap_err()
+        .to_string();
+        fs::remove_file(&config_path).unwrap();
+
+        assert!(
+            error.starts_with("IOCTL_TEST is defined twice in")
+                && error.ends_with("(lines 3 and 9)"),
+            "{error}"
+        );
+    }
+}
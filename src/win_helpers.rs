@@ -7,64 +7,147 @@ use windows::{
 };
 use windows_strings::HSTRING;
 
-pub fn open_device_handle(device_name: &String, overlapped: bool) -> windows::core::Result<HANDLE> {
+use crate::open_options::OpenOptions;
+use crate::transport::{DeviceTransport, IoctlStatus};
+use std::io;
+
+/// Transport that sends IOCTLs to real devices with CreateFileW and DeviceIoControl
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WindowsTransport;
+
+impl DeviceTransport for WindowsTransport {
+    type Handle = HANDLE;
+
+    fn open(
+        &self,
+        device_name: &str,
+        options: &OpenOptions,
+        overlapped: bool,
+    ) -> io::Result<HANDLE> {
+        Ok(open_device_handle(device_name, options, overlapped)?)
+    }
+
+    fn ioctl(
+        &self,
+        handle: &HANDLE,
+        code: u32,
+        input_buffer: Option<&[u8]>,
+        input_buffer_size: usize,
+        output_buffer: Option<&mut [u8]>,
+        output_buffer_size: usize,
+    ) -> io::Result<IoctlStatus> {
+        let mut bytes_returned = 0;
+        let result = send_device_io_control(
+            *handle,
+            code,
+            input_buffer,
+            input_buffer_size,
+            output_buffer,
+            output_buffer_size,
+            &mut bytes_returned,
+        );
+
+        Ok(IoctlStatus {
+            bytes_returned: bytes_returned as usize,
+            status: match result {
+                Ok(()) => 0,
+                Err(e) => win32_error_code(&e),
+            },
+        })
+    }
+
+    fn close(&self, handle: HANDLE) -> io::Result<()> {
+        unsafe { CloseHandle(handle)? };
+
+        Ok(())
+    }
+}
+
+/// Gets the Win32 error code back out of an error's HRESULT. Anything that didn't come from
+/// a Win32 error code is left as the HRESULT.
+fn win32_error_code(error: &windows::core::Error) -> i32 {
+    let hresult = error.code().0 as u32;
+
+    match hresult & 0xFFFF_0000 {
+        0x8007_0000 => (hresult & 0xFFFF) as i32,
+        _ => hresult as i32,
+    }
+}
+
+/// Opens a handle to the device with the given CreateFileW options. FILE_FLAG_OVERLAPPED is
+/// added to the flags for overlapped IOCTLs.
+pub fn open_device_handle(
+    device_name: &str,
+    options: &OpenOptions,
+    overlapped: bool,
+) -> windows::core::Result<HANDLE> {
     let device_name_arg = HSTRING::from(device_name);
     let device_name_arg = PCWSTR::from_raw(device_name_arg.as_ptr());
 
+    let file_attributes = FILE_FLAGS_AND_ATTRIBUTES(options.flags_and_attributes_or_default());
     let file_attributes: FILE_FLAGS_AND_ATTRIBUTES = match overlapped {
-        false => FILE_ATTRIBUTE_NORMAL,
-        true => FILE_ATTRIBUTE_NORMAL | FILE_FLAG_OVERLAPPED,
+        false => file_attributes,
+        true => file_attributes | FILE_FLAG_OVERLAPPED,
     };
 
     unsafe {
         CreateFileW(
             device_name_arg,
-            GENERIC_READ.0 | GENERIC_WRITE.0, // This will need to be configurable.
-            FILE_SHARE_NONE,
+            options.desired_access_or_default(),
+            FILE_SHARE_MODE(options.share_mode_or_default()),
             None,
-            OPEN_EXISTING,
+            FILE_CREATION_DISPOSITION(options.creation_disposition_or_default()),
             file_attributes,
             None,
         )
     }
 }
 
+/// Calls DeviceIoControl with the given buffers. The sizes passed are independent of the
+/// lengths of the buffers, so that size confusion can be tested, and a None buffer is passed
+/// as NULL.
+///
+/// The number of bytes that the driver reported writing to the output buffer is stored in
+/// `bytes_returned` even if the call fails, as drivers can report bytes along with an error.
 pub fn send_device_io_control(
     device_handle: HANDLE,
     ioctl_code: u32,
-    input_buffer: &Vec<u8>,
+    input_buffer: Option<&[u8]>,
     input_buffer_size: usize,
+    output_buffer: Option<&mut [u8]>,
     output_buffer_size: usize,
-) -> windows::core::Result<Vec<u8>> {
-    let mut bytes_returned: u32 = 0;
-    let output_buffer = vec![0; output_buffer_size];
+    bytes_returned: &mut u32,
+) -> windows::core::Result<()> {
+    *bytes_returned = 0;
 
     unsafe {
         DeviceIoControl(
             device_handle,
             ioctl_code,
-            Some(input_buffer.as_ptr() as *const _),
+            input_buffer.map(|buffer| buffer.as_ptr() as *const _),
             input_buffer_size.try_into()?,
-            Some(output_buffer.as_ptr() as *mut _),
+            output_buffer.map(|buffer| buffer.as_mut_ptr() as *mut _),
             output_buffer_size.try_into()?,
-            Some(&mut bytes_returned),
+            Some(bytes_returned),
             None,
         )?;
     }
 
-    Ok(output_buffer)
+    Ok(())
 }
 
+/// Calls DeviceIoControl on a handle opened for overlapped I/O. The number of bytes written
+/// to the output buffer is only known once the IOCTL has completed, so is 0 unless waiting.
 pub fn send_device_io_control_overlapped(
     device_handle: HANDLE,
     ioctl_code: u32,
-    input_buffer: &Vec<u8>,
+    input_buffer: Option<&[u8]>,
     input_buffer_size: usize,
+    output_buffer: Option<&mut [u8]>,
     output_buffer_size: usize,
     wait_overlapped: bool,
-) -> windows::core::Result<Vec<u8>> {
+) -> windows::core::Result<u32> {
     let mut bytes_returned: u32 = 0;
-    let output_buffer = vec![0; output_buffer_size];
 
     unsafe {
         let mut overlapped = OVERLAPPED {
@@ -82,9 +165,9 @@ pub fn send_device_io_control_overlapped(
         DeviceIoControl(
             device_handle,
             ioctl_code,
-            Some(input_buffer.as_ptr() as *const _),
+            input_buffer.map(|buffer| buffer.as_ptr() as *const _),
             input_buffer_size.try_into()?,
-            Some(output_buffer.as_ptr() as *mut _),
+            output_buffer.map(|buffer| buffer.as_mut_ptr() as *mut _),
             output_buffer_size.try_into()?,
             Some(&mut bytes_returned),
             Some(&mut overlapped),
@@ -93,11 +176,10 @@ pub fn send_device_io_control_overlapped(
         if wait_overlapped {
             WaitForSingleObject(overlapped.hEvent, 2000);
 
-            let mut bytes_copied = 0;
-            GetOverlappedResult(device_handle, &overlapped, &mut bytes_copied, false)?;
+            GetOverlappedResult(device_handle, &overlapped, &mut bytes_returned, false)?;
         }
         // How do we actually handle not waiting...
     }
 
-    Ok(output_buffer)
+    Ok(bytes_returned)
 }
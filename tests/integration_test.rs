@@ -1,19 +1,20 @@
-use ioctiller::dispatch::Dispatcher;
+use ioctiller::dispatch::{Dispatcher, FuzzIoctlDispatcher, SingleIoctlDispatcher};
+use ioctiller::error::DispatchError;
+use ioctiller::mock::{MockFault, MockReply, MockTransport};
+use ioctiller::report::Reporter;
 use ioctiller::{Cli, Config, Ioctl};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 pub struct TestDispatcher<'a> {
-    pub device_name: String,
     pub ioctl: &'a Ioctl,
 }
 
 impl<'a> Dispatcher for TestDispatcher<'a> {
-    fn dispatch(&self) -> windows::core::Result<()> {
-        let input = self.ioctl.build_input_buffer().unwrap();
+    fn dispatch(&self, _reporter: &dyn Reporter) -> Result<(), DispatchError> {
+        let input = self.ioctl.build_input_buffer()?;
 
-        assert_eq!(
-            self.device_name,
-            "\\\\.\\GLOBALROOT\\Device\\Beep".to_string()
-        );
+        assert_eq!(self.ioctl.device_name(), "\\\\.\\GLOBALROOT\\Device\\Beep");
 
         assert_eq!(input.len(), 0x40);
 
@@ -30,19 +31,26 @@ impl<'a> Dispatcher for TestDispatcher<'a> {
     }
 }
 
-#[test]
-fn load_config() {
-    let conf_path = std::path::PathBuf::from(r"tests\test.toml");
+fn load_test_config() -> Config {
+    let conf_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("test.toml");
     let cli = Cli {
         file_path: conf_path,
+        validate: false,
+        format: None,
     };
 
-    let config = Config::build(&cli).unwrap();
+    Config::build(&cli).unwrap()
+}
+
+#[test]
+fn load_config() {
+    let config = load_test_config();
 
     let selected_ioctl: &Ioctl = &config.ioctls[0];
 
     let test_dispatcher = TestDispatcher {
-        device_name: config.device_name,
         ioctl: selected_ioctl,
     };
 
@@ -50,3 +58,419 @@ fn load_config() {
         panic!("Error calling send with test_dispatcher: {e}");
     }
 }
+
+#[test]
+fn send_single_through_mock_transport() {
+    let config = load_test_config();
+    let ioctl = &config.ioctls[0];
+
+    let transport = MockTransport::new().output(&[0x41; 0x20]);
+    let dispatcher = SingleIoctlDispatcher {
+        ioctl,
+        transport: transport.clone(),
+    };
+
+    let outcomes = ioctiller::send_single(&dispatcher).unwrap();
+    assert_eq!(1, outcomes.len());
+    assert_eq!("IOCTL_TEST", outcomes[0].ioctl);
+    assert_eq!(0x222000, outcomes[0].code);
+    assert_eq!(Some(ioctl.build_input_buffer().unwrap()), outcomes[0].input);
+    assert_eq!(Some(vec![0x41; 0x10]), outcomes[0].output);
+    assert!(!outcomes[0].failed());
+
+    let sent = transport.sent();
+    assert_eq!(1, sent.len());
+    assert_eq!(config.device_name, sent[0].device_name);
+    assert_eq!(0x222000, sent[0].code);
+    assert_eq!(0xC0000000, sent[0].desired_access);
+    assert_eq!(
+        Some(ioctl.build_input_buffer().unwrap()),
+        sent[0].input_buffer
+    );
+    assert_eq!(0x40, sent[0].input_buffer_size);
+    assert_eq!(0x10, sent[0].output_buffer_size);
+    assert_eq!(0, transport.open_handles());
+
+    // A failed IOCTL is returned in the outcome, and the handle is still closed
+    let transport = MockTransport::new().status(5);
+    let dispatcher = SingleIoctlDispatcher {
+        ioctl,
+        transport: transport.clone(),
+    };
+
+    let outcomes = ioctiller::send_single(&dispatcher).unwrap();
+    assert_eq!(5, outcomes[0].status.status);
+    assert!(outcomes[0].failed());
+    assert!(!outcomes[0].guard_page_fault);
+    assert_eq!(0, transport.open_handles());
+
+    // Failing to open the device is still an error
+    let transport = MockTransport::new().max_opens(0);
+    let dispatcher = SingleIoctlDispatcher { ioctl, transport };
+
+    assert!(matches!(
+        ioctiller::send_single(&dispatcher),
+        Err(DispatchError::Device(_))
+    ));
+}
+
+#[test]
+fn send_single_with_bad_buffer_leaves_no_handle_open() {
+    let conf_path = std::env::temp_dir().join("ioctiller_bad_buffer.toml");
+    std::fs::write(
+        &conf_path,
+        r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_OUT_OF_BOUNDS"
+code = 0x222000
+input_buffer_size = 0x4
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x2, type = "U32", value = 0x1 },
+]
+"#,
+    )
+    .unwrap();
+
+    let config = Config::build(&Cli {
+        file_path: conf_path.clone(),
+        validate: false,
+        format: None,
+    })
+    .unwrap();
+    std::fs::remove_file(&conf_path).unwrap();
+
+    let transport = MockTransport::new();
+    let dispatcher = SingleIoctlDispatcher {
+        ioctl: &config.ioctls[0],
+        transport: transport.clone(),
+    };
+
+    assert!(matches!(
+        ioctiller::send_single(&dispatcher),
+        Err(DispatchError::Buffer(_))
+    ));
+    assert_eq!(0, transport.open_handles());
+    assert!(transport.sent().is_empty());
+}
+
+#[test]
+fn file_entries_in_repeat_and_array_are_relative_to_the_config() {
+    let config_dir = std::env::temp_dir().join("ioctiller_nested_file_entries");
+    std::fs::create_dir_all(&config_dir).unwrap();
+    std::fs::write(config_dir.join("record.bin"), [0x11, 0x22]).unwrap();
+    std::fs::write(config_dir.join("value.bin"), [0x33, 0x44]).unwrap();
+
+    let conf_path = config_dir.join("nested.toml");
+    std::fs::write(
+        &conf_path,
+        r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_NESTED"
+code = 0x222000
+input_buffer_size = 0x8
+output_buffer_size = 0x0
+input_buffer_content = [
+    { offset = 0x0, type = "Repeat", count = 2, stride = 2, entries = [
+        { offset = 0x0, type = "File", path = "record.bin" },
+    ] },
+    { offset = 0x4, type = "Array", values = [
+        { type = "File", path = "value.bin" },
+        { type = "File", path = "record.bin", length = 1 },
+    ] },
+]
+"#,
+    )
+    .unwrap();
+
+    // Loaded from outside the config's directory, the paths must still be found
+    let config = Config::build(&Cli {
+        file_path: conf_path,
+        validate: false,
+        format: None,
+    })
+    .unwrap();
+    let input = config.ioctls[0].build_input_buffer();
+    std::fs::remove_dir_all(&config_dir).unwrap();
+
+    assert_eq!(
+        vec![0x11, 0x22, 0x11, 0x22, 0x33, 0x44, 0x11, 0x00],
+        input.unwrap()
+    );
+}
+
+#[test]
+fn fuzz_single_through_mock_transport() {
+    let config = load_test_config();
+    let ioctl = &config.ioctls[0];
+
+    // A fuzzing thread runs until opening the device fails
+    let transport = MockTransport::new().max_opens(50);
+    let dispatcher = FuzzIoctlDispatcher {
+        ioctl: ioctl.clone(),
+        transport: transport.clone(),
+    };
+
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+    assert!(matches!(
+        ioctiller::fuzz_single(dispatcher, 1, Arc::clone(&outcomes)),
+        Err(DispatchError::Device(_))
+    ));
+
+    let sent = transport.sent();
+    assert_eq!(50, sent.len());
+    assert_eq!(50, outcomes.lock().unwrap().len());
+    assert_eq!(0, transport.open_handles());
+    // Mutated inputs change length, and the driver is told the length of each
+    assert!(sent.iter().all(|sent_ioctl| sent_ioctl.code == 0x222000
+        && sent_ioctl.input_buffer.as_ref().map(Vec::len) == Some(sent_ioctl.input_buffer_size)));
+}
+
+#[test]
+fn fuzz_single_with_guard_page_reports_no_false_faults() {
+    let conf_path = std::env::temp_dir().join("ioctiller_fuzz_guard_page.toml");
+    std::fs::write(
+        &conf_path,
+        r#"device_name = '\\.\Test'
+
+[[ioctls]]
+name = "IOCTL_GUARDED"
+code = 0x222000
+input_buffer_size = 0x20
+output_buffer_size = 0x8
+guard_page = "end"
+input_buffer_content = [
+    { offset = 0x0, type = "String8", value = "AAAABBBBCCCCDDDDEEEEFFFFGGGGHHHH" },
+]
+"#,
+    )
+    .unwrap();
+
+    let config = Config::build(&Cli {
+        file_path: conf_path.clone(),
+        validate: false,
+        format: None,
+    })
+    .unwrap();
+    std::fs::remove_file(&conf_path).unwrap();
+
+    // Reading as many bytes as the driver is told about runs into the guard page after any
+    // input that is shorter than that
+    let transport = MockTransport::new()
+        .max_opens(200)
+        .handler(0x222000, |sent_ioctl| MockReply {
+            fault: sent_ioctl
+                .input_buffer
+                .as_ref()
+                .is_some_and(|input_buffer| input_buffer.len() < sent_ioctl.input_buffer_size)
+                .then_some(MockFault::AccessViolation),
+            ..Default::default()
+        });
+    let dispatcher = FuzzIoctlDispatcher {
+        ioctl: config.ioctls[0].clone(),
+        transport: transport.clone(),
+    };
+
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+    assert!(matches!(
+        ioctiller::fuzz_single(dispatcher, 1, Arc::clone(&outcomes)),
+        Err(DispatchError::Device(_))
+    ));
+
+    assert!(transport.sent().iter().all(|sent_ioctl| {
+        sent_ioctl.input_buffer.as_ref().map(Vec::len) == Some(sent_ioctl.input_buffer_size)
+    }));
+    assert!(
+        outcomes
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|outcome| !outcome.guard_page_fault)
+    );
+}
+
+#[test]
+fn send_single_trims_output_to_bytes_returned() {
+    let config = load_test_config();
+    let ioctl = &config.ioctls[0];
+
+    // The kernel address is past what the driver reported writing, so isn't a leak
+    let transport = MockTransport::new().reply(
+        0x222000,
+        MockReply {
+            output: [[0x41; 8], 0xFFFFF80000000000u64.to_le_bytes()].concat(),
+            bytes_returned: Some(4),
+            ..Default::default()
+        },
+    );
+    let dispatcher = SingleIoctlDispatcher { ioctl, transport };
+
+    let outcomes = ioctiller::send_single(&dispatcher).unwrap();
+    assert_eq!([0x41; 4], outcomes[0].returned_output());
+    assert_eq!(12, outcomes[0].rest_of_output().len());
+    assert!(outcomes[0].leaks.is_empty());
+    assert!(!outcomes[0].is_finding());
+
+    // Reporting more than the 0x10 byte output buffer is a finding
+    let transport = MockTransport::new().reply(
+        0x222000,
+        MockReply {
+            bytes_returned: Some(0x11),
+            ..Default::default()
+        },
+    );
+    let dispatcher = SingleIoctlDispatcher { ioctl, transport };
+
+    let outcomes = ioctiller::send_single(&dispatcher).unwrap();
+    assert_eq!(0x10, outcomes[0].returned_output().len());
+    assert!(outcomes[0].overreported_bytes_returned());
+    assert!(outcomes[0].is_finding());
+
+    // Even when the driver also fails the IOCTL
+    let transport = MockTransport::new().reply(
+        0x222000,
+        MockReply {
+            status: 5,
+            bytes_returned: Some(0x100),
+            ..Default::default()
+        },
+    );
+    let dispatcher = SingleIoctlDispatcher { ioctl, transport };
+
+    let outcomes = ioctiller::send_single(&dispatcher).unwrap();
+    assert!(outcomes[0].status.error().is_some());
+    assert!(outcomes[0].overreported_bytes_returned());
+    assert!(outcomes[0].is_finding());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn linux_transport_sends_to_pty() {
+    use ioctiller::linux_helpers::LinuxTransport;
+    use ioctiller::open_options::OpenOptions;
+    use ioctiller::transport::DeviceTransport;
+
+    const TIOCGWINSZ: u32 = 0x5413;
+    const TIOCSWINSZ: u32 = 0x5414;
+
+    let transport = LinuxTransport;
+    let pty = transport
+        .open("/dev/ptmx", &OpenOptions::default(), false)
+        .unwrap();
+
+    // struct winsize { rows, cols, xpixel, ypixel }
+    let window_size = [24u16.to_ne_bytes(), 80u16.to_ne_bytes(), [0; 2], [0; 2]].concat();
+    let status = transport
+        .ioctl(&pty, TIOCSWINSZ, Some(&window_size), 8, None, 0)
+        .unwrap();
+    assert!(status.succeeded(), "{:?}", status.error());
+
+    let mut output_buffer = [0xFF; 8];
+    let status = transport
+        .ioctl(&pty, TIOCGWINSZ, None, 0, Some(&mut output_buffer), 8)
+        .unwrap();
+    assert_eq!(8, status.bytes_returned);
+    assert_eq!(window_size, output_buffer);
+
+    // ENOTTY
+    let status = transport.ioctl(&pty, 0x1, None, 0, None, 0).unwrap();
+    assert_eq!(25, status.status);
+
+    transport.close(pty).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn send_single_to_pty() {
+    let conf_path = std::env::temp_dir().join("ioctiller_pty.toml");
+    std::fs::write(
+        &conf_path,
+        r#"device_name = "/dev/ptmx"
+
+[[ioctls]]
+name = "TIOCGPTN"
+code = { dir = "read", type = "'T'", nr = 0x30, size = 4 }
+input_buffer_size = 0x0
+output_buffer_size = 0x4
+null_input = true
+
+[[ioctls]]
+name = "TIOCGWINSZ"
+code = 0x5413
+input_buffer_size = 0x0
+output_buffer_size = 0x8
+null_input = true
+"#,
+    )
+    .unwrap();
+
+    let config = Config::build(&Cli {
+        file_path: conf_path.clone(),
+        validate: false,
+        format: None,
+    })
+    .unwrap();
+    std::fs::remove_file(&conf_path).unwrap();
+
+    for ioctl in &config.ioctls {
+        let dispatcher = SingleIoctlDispatcher {
+            ioctl,
+            transport: ioctiller::transport::NativeTransport,
+        };
+
+        ioctiller::send_single(&dispatcher).unwrap();
+    }
+}
+
+#[test]
+fn mock_driver_end_to_end() {
+    let config = load_test_config();
+    let transport = MockTransport::from_toml(include_str!("mock_driver.toml")).unwrap();
+
+    // The leak and the guard page fault are reported as findings rather than errors
+    let mut outcomes = Vec::new();
+    for ioctl in &config.ioctls {
+        let dispatcher = SingleIoctlDispatcher {
+            ioctl,
+            transport: transport.clone(),
+        };
+
+        outcomes.extend(ioctiller::send_single(&dispatcher).unwrap());
+    }
+
+    assert_eq!(vec![(8, 0xFFFFF80000000000)], outcomes[0].leaks);
+    assert!(!outcomes[0].guard_page_fault);
+    assert!(outcomes[0].is_finding());
+    assert!(outcomes[1].guard_page_fault);
+    assert!(outcomes[1].leaks.is_empty());
+    assert!(outcomes[1].is_finding());
+
+    // Fuzzing stops once the driver has crashed and the device can't be opened
+    let dispatcher = FuzzIoctlDispatcher {
+        ioctl: config.ioctls[1].clone(),
+        transport: transport.clone(),
+    };
+
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+    assert!(matches!(
+        ioctiller::fuzz_single(dispatcher, 1, Arc::clone(&outcomes)),
+        Err(DispatchError::Device(_))
+    ));
+    assert!(transport.crashed());
+    // The IOCTL that crashed the driver never completed
+    assert_eq!(2, outcomes.lock().unwrap().len());
+    assert_eq!(
+        vec![0x222000, 0x222004, 0x222004, 0x222004, 0x222004],
+        transport
+            .sent()
+            .iter()
+            .map(|sent_ioctl| sent_ioctl.code)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(0, transport.open_handles());
+}